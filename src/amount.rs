@@ -0,0 +1,177 @@
+use crate::error::{ChainError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Decimal places of a coin, used when displaying and parsing amounts.
+pub const DECIMALS: u32 = 8;
+
+/// A non-negative quantity of base units. Arithmetic is checked, so sums that
+/// would overflow surface as errors instead of wrapping.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_base(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    /// `coins` whole coins, or `None` on overflow.
+    pub fn from_coins(coins: u64) -> Option<Amount> {
+        coins.checked_mul(10u64.pow(DECIMALS)).map(Amount)
+    }
+
+    pub fn to_base(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Amount> {
+        self.0.checked_mul(factor).map(Amount)
+    }
+
+    /// Adds up `amounts`, erroring if the total overflows.
+    pub fn sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Result<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, |total, amount| {
+            total
+                .checked_add(amount)
+                .ok_or_else(|| ChainError::InvalidAmount(String::from("sum overflows")))
+        })
+    }
+
+    /// Parses a decimal string such as `1.25` with `decimals` places.
+    pub fn parse(s: &str, decimals: u32) -> Result<Amount> {
+        let invalid = || ChainError::InvalidAmount(s.to_string());
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if whole.is_empty() && fraction.is_empty()
+            || fraction.len() > decimals as usize
+            || !whole
+                .chars()
+                .chain(fraction.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        let scale = 10u64.checked_pow(decimals).ok_or_else(invalid)?;
+        let whole: u64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| invalid())?
+        };
+        let fraction: u64 = if fraction.is_empty() {
+            0
+        } else {
+            let padding = 10u64.pow(decimals - fraction.len() as u32);
+            fraction.parse::<u64>().map_err(|_| invalid())? * padding
+        };
+        whole
+            .checked_mul(scale)
+            .and_then(|units| units.checked_add(fraction))
+            .map(Amount)
+            .ok_or_else(invalid)
+    }
+
+    /// Formats with `decimals` places, at most 19, dropping trailing zeros.
+    pub fn format(self, decimals: u32) -> String {
+        let scale = 10u64.pow(decimals);
+        let whole = self.0 / scale;
+        let fraction = self.0 % scale;
+        if fraction == 0 {
+            return whole.to_string();
+        }
+        let fraction = format!("{:0width$}", fraction, width = decimals as usize);
+        format!("{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format(DECIMALS))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ChainError;
+
+    fn from_str(s: &str) -> Result<Amount> {
+        Amount::parse(s, DECIMALS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coins(s: &str) -> Result<Amount> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_partial_decimals() {
+        assert_eq!(coins("1.").unwrap(), Amount::from_coins(1).unwrap());
+        assert_eq!(coins(".5").unwrap(), Amount::from_base(50_000_000));
+        assert_eq!(coins("0.00000001").unwrap(), Amount::from_base(1));
+        assert!(coins(".").is_err());
+        assert!(coins("").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_amounts() {
+        assert!(coins("0.000000001").is_err());
+        assert!(coins("1.2.3").is_err());
+        assert!(coins("-1").is_err());
+        assert!(coins("+1").is_err());
+        assert!(coins("1e3").is_err());
+        assert!(coins(" 1").is_err());
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert_eq!(
+            coins("184467440737.09551615").unwrap(),
+            Amount::from_base(u64::MAX)
+        );
+        assert!(coins("184467440737.09551616").is_err());
+        assert!(coins("184467440738").is_err());
+        assert!(coins("99999999999999999999").is_err());
+        assert_eq!(Amount::from_coins(u64::MAX / 100_000_000 + 1), None);
+    }
+
+    #[test]
+    fn formatting_round_trips() {
+        for units in [0, 1, 10, 50_000_000, 100_000_000, 123_456_789, u64::MAX] {
+            let amount = Amount::from_base(units);
+            assert_eq!(coins(&amount.to_string()).unwrap(), amount);
+        }
+        assert_eq!(Amount::from_base(150_000_000).to_string(), "1.5");
+        assert_eq!(Amount::from_base(1).to_string(), "0.00000001");
+        assert_eq!(Amount::from_coins(2).unwrap().to_string(), "2");
+        assert_eq!(Amount::from_base(1234).format(2), "12.34");
+        assert_eq!(Amount::parse("12.34", 2).unwrap(), Amount::from_base(1234));
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let max = Amount::from_base(u64::MAX);
+        let one = Amount::from_base(1);
+        assert_eq!(one.checked_add(one), Some(Amount::from_base(2)));
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!(one.checked_sub(one), Some(Amount::ZERO));
+        assert_eq!(Amount::ZERO.checked_sub(one), None);
+        assert_eq!(max.checked_mul(2), None);
+        assert_eq!(Amount::sum([one, one, one]).unwrap(), Amount::from_base(3));
+        assert_eq!(Amount::sum([]).unwrap(), Amount::ZERO);
+        assert!(Amount::sum([max, one]).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::error::{ChainError, Result};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const CHECKSUM_SIZE: usize = 4;
+
+/// Encodes `payload` followed by the first four bytes of its double SHA256.
+pub fn encode_check(payload: &[u8]) -> String {
+    let mut data = payload.to_vec();
+    data.extend_from_slice(&checksum(payload));
+    encode(&data)
+}
+
+/// Decodes a base58check string, rejecting a checksum that does not match,
+/// e.g. from a mistyped character.
+pub fn decode_check(s: &str) -> Result<Vec<u8>> {
+    let mut data = decode(s)?;
+    if data.len() < CHECKSUM_SIZE {
+        return Err(ChainError::Encoding(String::from(
+            "base58 string is too short",
+        )));
+    }
+    let check = data.split_off(data.len() - CHECKSUM_SIZE);
+    if check != checksum(&data) {
+        return Err(ChainError::Encoding(String::from(
+            "base58 checksum does not match",
+        )));
+    }
+    Ok(data)
+}
+
+fn encode(data: &[u8]) -> String {
+    // base 58 digits, least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = data.iter().take_while(|&&byte| byte == 0).count();
+    std::iter::repeat_n('1', zeros)
+        .chain(
+            digits
+                .iter()
+                .rev()
+                .map(|&digit| ALPHABET[digit as usize] as char),
+        )
+        .collect()
+}
+
+fn decode(s: &str) -> Result<Vec<u8>> {
+    // bytes, least significant first
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.chars() {
+        let mut carry = ALPHABET
+            .iter()
+            .position(|&a| a as char == c)
+            .ok_or_else(|| ChainError::Encoding(format!("'{}' is not a base58 character", c)))?
+            as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = s.chars().take_while(|&c| c == '1').count();
+    Ok(std::iter::repeat_n(0, zeros)
+        .chain(bytes.into_iter().rev())
+        .collect())
+}
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    let mut hash = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result(&mut hash);
+    hasher.reset();
+    hasher.input(&hash);
+    hasher.result(&mut hash);
+    hash[..CHECKSUM_SIZE].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bitcoin's example WIF private key: version 0x80 followed by the key
+    const KEY: &str = "0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d";
+    const WIF: &str = "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ";
+
+    #[test]
+    fn encodes_reference_wif() {
+        let mut payload = vec![0x80];
+        payload.extend(hex::decode(KEY).unwrap());
+        assert_eq!(encode_check(&payload), WIF);
+        assert_eq!(decode_check(WIF).unwrap(), payload);
+    }
+
+    #[test]
+    fn keeps_leading_zeros() {
+        let payload = [0; 21];
+        let encoded = encode_check(&payload);
+        assert_eq!(encoded, "1111111111111111111114oLvT2");
+        assert_eq!(decode_check(&encoded).unwrap(), payload);
+    }
+
+    #[test]
+    fn rejects_mistyped_strings() {
+        // last character changed
+        assert!(decode_check("5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTK").is_err());
+        // '0' is not in the alphabet
+        assert!(decode_check("5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyT0").is_err());
+        assert!(decode_check("1").is_err());
+    }
+}
@@ -1,14 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-//use bincode::Result;
 use log::info;
 
-use crate::block::Block;
-use crate::block::TARGET_HEXT;
-use crate::error::Result;
-use crate::transaction::Transaction;
-use crate::tx::{TxInput, TxOutput};
-use failure::format_err;
+use crate::amount::Amount;
+use crate::block::{Block, MAX_BLOCK_SIGOPS};
+use crate::coinselect::Utxo;
+use crate::transaction::{SUBSIDY, Transaction};
+use crate::tx::{RelativeLock, TxOutput};
+use crate::error::{ChainError, Result};
+use crate::script::Script;
+
+/// Blocks, counting back from the tip, whose median timestamp lock times
+/// are checked against.
+const MEDIAN_TIME_SPAN: usize = 11;
 
 #[derive(Debug, Clone)]
 pub struct Blockchain {
@@ -23,11 +27,14 @@ pub struct BlockchainIter<'a> {
 
 impl Blockchain {
     pub fn new() -> Result<Blockchain> {
+        Blockchain::open("data/blocks")
+    }
+
+    /// Opens the chain stored at `path`, which must have been created.
+    pub fn open(path: &str) -> Result<Blockchain> {
         info!("open the blockchain");
-        let db = sled::open("data/blocks")?;
-        let hash = db
-            .get("LAST")?
-            .expect("Must create a new block database first");
+        let db = sled::open(path)?;
+        let hash = db.get("LAST")?.ok_or(ChainError::NotInitialized)?;
         info!("found block database");
         let lasthash = String::from_utf8(hash.to_vec())?;
         Ok(Blockchain {
@@ -37,11 +44,16 @@ impl Blockchain {
     }
 
     pub fn create_blockchain(address: String) -> Result<Blockchain> {
+        Blockchain::create_blockchain_at("data/blocks", address)
+    }
+
+    /// Creates a chain at `path` whose genesis block pays `address`.
+    pub fn create_blockchain_at(path: &str, address: String) -> Result<Blockchain> {
         info!("Creating a new blockchain");
-        let db = sled::open("data/blocks")?;
+        let db = sled::open(path)?;
         info!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from("")).unwrap();
-        let genesis = Block::new_genesis_block(cbtx);
+        let cbtx = Transaction::new_coinbase(address, String::from(""), Amount::ZERO)?;
+        let genesis = Block::new_genesis_block(cbtx)?;
         db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
         db.insert("LAST", genesis.get_hash().as_bytes())?;
         let bc = Blockchain {
@@ -52,9 +64,10 @@ impl Blockchain {
         Ok(bc)
     }
     pub fn add_block(&mut self, data: Vec<Transaction>) -> Result<()> {
-        let lasthash = self.db.get("LAST")?.unwrap();
-        let new_block = Block::new_block(data, String::from_utf8(lasthash.to_vec())?, TARGET_HEXT)?;
-        // println!("{:?}", new_block);
+        self.validate_transactions(&data)?;
+        let lasthash = self.db.get("LAST")?.ok_or(ChainError::NotInitialized)?;
+        let height = self.get_best_height()? + 1;
+        let new_block = Block::new_block(data, String::from_utf8(lasthash.to_vec())?, height)?;
         self.db
             .insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
         self.db.insert("LAST", new_block.get_hash().as_bytes())?;
@@ -63,6 +76,13 @@ impl Blockchain {
         Ok(())
     }
 
+    pub fn get_best_height(&self) -> Result<usize> {
+        let lasthash = self.db.get("LAST")?.ok_or(ChainError::NotInitialized)?;
+        let last_data = self.db.get(lasthash)?.ok_or(ChainError::NotInitialized)?;
+        let last_block: Block = bincode::deserialize(&last_data)?;
+        Ok(last_block.get_height())
+    }
+
     pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
         for block in self.iter() {
             for tx in block.get_transaction() {
@@ -71,101 +91,266 @@ impl Blockchain {
                 }
             }
         }
-        Err(format_err!("Transaction not found"))
+        Err(ChainError::TransactionNotFound(id.to_string()))
+    }
+
+    /// Height of the block containing transaction `id`, and the median time
+    /// past of the block before it, from which relative time locks count.
+    /// Genesis outputs count from the genesis block's own time.
+    pub fn find_transaction_block(&self, id: &str) -> Result<(usize, u64)> {
+        for block in self.iter() {
+            if block.get_transaction().iter().any(|tx| tx.id == id) {
+                let time = match block.get_height() {
+                    0 => (block.get_timestamp() / 1000) as u64,
+                    _ => self.median_time_past(&block.get_prev_hash()),
+                };
+                return Ok((block.get_height(), time));
+            }
+        }
+        Err(ChainError::TransactionNotFound(id.to_string()))
+    }
+
+    /// Median timestamp, in unix seconds, of the tip and the blocks before it.
+    /// Time locks on the next block are checked against it rather than the
+    /// clock, so every node reaches the same verdict and no single miner can
+    /// move it far.
+    pub fn get_median_time_past(&self) -> u64 {
+        self.median_time_past(&self.current_hash)
+    }
+
+    fn median_time_past(&self, hash: &str) -> u64 {
+        let blocks = BlockchainIter {
+            current_hash: hash.to_string(),
+            bc: self,
+        };
+        let mut times: Vec<u64> = blocks
+            .take(MEDIAN_TIME_SPAN)
+            .map(|block| (block.get_timestamp() / 1000) as u64)
+            .collect();
+        times.sort();
+        times.get(times.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// Every `(txid, vout)` already spent by a confirmed transaction.
+    pub fn find_spent_outputs(&self) -> HashSet<(String, i32)> {
+        let mut spent = HashSet::new();
+        for block in self.iter() {
+            for tx in block.get_transaction() {
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        spent.insert((vin.txid.clone(), vin.vout));
+                    }
+                }
+            }
+        }
+        spent
     }
 
-    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+    pub fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+        self.get_prev_txs_with(tx, &HashMap::new())
+    }
+
+    /// Finds the transactions spent by `tx`, looking in the unconfirmed
+    /// `pending` transactions before the chain.
+    pub fn get_prev_txs_with(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<String, Transaction>,
+    ) -> Result<HashMap<String, Transaction>> {
         let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
         for vin in &tx.vin {
-            let prev_tx = self.find_transaction(&vin.txid)?;
+            let prev_tx = match pending.get(&vin.txid) {
+                Some(prev_tx) => prev_tx.clone(),
+                None => self.find_transaction(&vin.txid)?,
+            };
             prev_txs.insert(prev_tx.id.clone(), prev_tx);
         }
         Ok(prev_txs)
     }
 
-    pub fn verify_transaction(&self, tx: &mut Transaction) -> Result<bool> {
-        let prev_txs = self.get_prev_txs(tx)?;
-        tx.verify(prev_txs)
+    /// Verifies `tx` for inclusion in the next block, after the unconfirmed
+    /// `pending` transactions, erroring if a time lock does not allow it yet.
+    pub fn verify_transaction(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<String, Transaction>,
+    ) -> Result<bool> {
+        let prev_txs = self.get_prev_txs_with(tx, pending)?;
+        let height = self.get_best_height()? + 1;
+        let time = self.get_median_time_past();
+        self.check_locks(tx, height, time, pending)?;
+        tx.verify(&prev_txs, height, time)
+    }
+
+    /// Checks `txs` as the contents of the next block: an optional leading
+    /// coinbase paying at most the subsidy plus fees, no double spends, and
+    /// valid locks and scripts. Transactions may spend earlier ones in `txs`.
+    pub fn validate_transactions(&self, txs: &[Transaction]) -> Result<()> {
+        let height = self.get_best_height()? + 1;
+        let time = self.get_median_time_past();
+        let mut spent = self.find_spent_outputs();
+        let mut pending: HashMap<String, Transaction> = HashMap::new();
+        let mut fees = Amount::ZERO;
+        let mut sigops = 0;
+        for (index, tx) in txs.iter().enumerate() {
+            if tx.is_coinbase() {
+                if index != 0 {
+                    return Err(ChainError::InvalidBlock(String::from(
+                        "coinbase must be the first transaction",
+                    )));
+                }
+                continue;
+            }
+            for vin in &tx.vin {
+                if !spent.insert((vin.txid.clone(), vin.vout)) {
+                    return Err(ChainError::InvalidTx(format!(
+                        "{} double spends {}:{}",
+                        tx.id, vin.txid, vin.vout
+                    )));
+                }
+            }
+            let prev_txs = self.get_prev_txs_with(tx, &pending)?;
+            self.check_locks(tx, height, time, &pending)?;
+            if !tx.verify(&prev_txs, height, time)? {
+                return Err(ChainError::InvalidTx(format!(
+                    "{} failed script verification",
+                    tx.id
+                )));
+            }
+            fees = Amount::sum([fees, tx.fee(&prev_txs)?])?;
+            sigops += tx.sigop_count(&prev_txs)?;
+            if sigops > MAX_BLOCK_SIGOPS {
+                return Err(ChainError::InvalidBlock(format!(
+                    "block runs more than {} signature checks",
+                    MAX_BLOCK_SIGOPS
+                )));
+            }
+            pending.insert(tx.id.clone(), tx.clone());
+        }
+        if let Some(coinbase) = txs.first().filter(|tx| tx.is_coinbase()) {
+            let reward = Amount::sum(coinbase.vout.iter().map(|out| out.value))?;
+            let available = Amount::sum([SUBSIDY, fees])?;
+            if reward > available {
+                return Err(ChainError::InvalidBlock(format!(
+                    "coinbase pays {} but only {} is available",
+                    reward, available
+                )));
+            }
+        }
+        Ok(())
     }
-     
+
+    /// Enforces the absolute lock time and every input's relative lock, for a
+    /// block at `height` whose median time past is `time`. Inputs spending
+    /// `pending` transactions count as confirmed at `height`.
+    pub fn check_locks(
+        &self,
+        tx: &Transaction,
+        height: usize,
+        time: u64,
+        pending: &HashMap<String, Transaction>,
+    ) -> Result<()> {
+        if !tx.is_final(height, time) {
+            return Err(ChainError::InvalidTx(format!(
+                "{} is locked until {}",
+                tx.id, tx.lock_time
+            )));
+        }
+        for vin in &tx.vin {
+            let lock = match vin.relative_lock() {
+                Some(lock) => lock,
+                None => continue,
+            };
+            let (prev_height, prev_time) = if pending.contains_key(&vin.txid) {
+                (height, time)
+            } else {
+                self.find_transaction_block(&vin.txid)?
+            };
+            let unlocked = match lock {
+                RelativeLock::Blocks(blocks) => height >= prev_height + blocks,
+                RelativeLock::Seconds(seconds) => time >= prev_time + seconds,
+            };
+            if !unlocked {
+                return Err(ChainError::InvalidTx(format!(
+                    "{} spends {}:{} before its relative lock expires",
+                    tx.id, vin.txid, vin.vout
+                )));
+            }
+        }
+        Ok(())
+    }
+
     pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
         let prev_txs = self.get_prev_txs(tx)?;
-        tx.sign(private_key, prev_txs)?;
+        tx.sign(private_key, &prev_txs)?;
         Ok(())
     }
 
-    fn find_unspent_transactions(&self, address: &str) -> Vec<Transaction> {
-        let mut spent_TXOs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut unspend_TXs: Vec<Transaction> = Vec::new();
+    pub fn sign_multisig_transaction(
+        &self,
+        tx: &mut Transaction,
+        private_key: &[u8],
+        redeem_script: &Script,
+    ) -> Result<()> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.sign_multisig(private_key, redeem_script, &prev_txs)
+    }
+
+    /// Collects the outputs locked by `script_pub_key` that no later input spends,
+    /// as `(txid, output index, output)`.
+    fn find_unspent_outputs(&self, script_pub_key: &Script) -> Vec<(String, i32, TxOutput)> {
+        let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
+        let mut unspent: Vec<(String, i32, TxOutput)> = Vec::new();
         for block in self.iter() {
-            for tx in block.get_transaction() {
-                'outputs: for (index, output) in tx.vout.iter().enumerate() {
-                    if let Some(ids) = spent_TXOs.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue 'outputs;
-                        }
+            // newest first, so spends are seen before the outputs they spend,
+            // including within a block where a child follows its parent
+            for tx in block.get_transaction().iter().rev() {
+                for (index, output) in tx.vout.iter().enumerate() {
+                    if let Some(ids) = spent_txos.get(&tx.id)
+                        && ids.contains(&(index as i32))
+                    {
+                        continue;
                     }
-                    if output.can_be_unlocked_with(address) {
-                        unspend_TXs.push(tx.clone());
+                    // data carriers can never be spent, so they are not UTXOs
+                    if output.script_pub_key == *script_pub_key
+                        && !output.script_pub_key.is_unspendable()
+                    {
+                        unspent.push((tx.id.clone(), index as i32, output.clone()));
                     }
                 }
 
                 if !tx.is_coinbase() {
                     for i in &tx.vin {
-                        if i.can_unlock_output_with(address) {
-                            spent_TXOs
-                                .entry(i.txid.clone())
-                                .or_insert_with(Vec::new)
-                                .push(i.vout);
-                        }
+                        spent_txos.entry(i.txid.clone()).or_default().push(i.vout);
                     }
                 }
             }
         }
-        unspend_TXs
-    }
-
-    pub fn find_UTXO(&self, address: &str) -> Vec<TxOutput> {
-        let mut UTXOs: Vec<TxOutput> = Vec::new();
-        let unspent_txs = self.find_unspent_transactions(address);
-        //println!("no of unspent txs are {:?}", unspent_txs.len());
-        for tx in unspent_txs {
-            //println!("unspent txs are {:?}", tx);
-            for out in tx.vout {
-                if out.can_be_unlocked_with(address) {
-                    UTXOs.push(out.clone());
-                }
-            }
-        }
-        UTXOs
+        unspent
+    }
+
+    pub fn find_utxo(&self, script_pub_key: &Script) -> Vec<TxOutput> {
+        self.find_unspent_outputs(script_pub_key)
+            .into_iter()
+            .map(|(_, _, out)| out)
+            .collect()
     }
 
+    /// Lists the unspent outputs of `script_pub_key` a payment may select from,
+    /// skipping those in `exclude`, e.g. outputs already spent in the mempool.
     pub fn find_spendable_outputs(
         &self,
-        address: &str,
-        amount: i32,
-    ) -> (i32, HashMap<String, Vec<i32>>) {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let unspent_txs = self.find_unspent_transactions(address);
-        let mut accumulated = 0;
-        for tx in unspent_txs {
-            let txid = tx.id.clone();
-            for (index, out) in tx.vout.iter().enumerate() {
-                if out.can_be_unlocked_with(address) && accumulated < amount {
-                    accumulated += out.value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(index as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![index as i32]);
-                        }
-                    }
-                }
-            }
-        }
-        (accumulated, unspent_outputs)
+        script_pub_key: &Script,
+        exclude: &HashSet<(String, i32)>,
+    ) -> Vec<Utxo> {
+        self.find_unspent_outputs(script_pub_key)
+            .into_iter()
+            .filter(|(txid, vout, _)| !exclude.contains(&(txid.clone(), *vout)))
+            .map(|(txid, vout, output)| Utxo { txid, vout, output })
+            .collect()
     }
 
-    pub fn iter(&self) -> BlockchainIter {
+    pub fn iter(&self) -> BlockchainIter<'_> {
         BlockchainIter {
             current_hash: self.current_hash.clone(),
             bc: self,
@@ -210,3 +395,61 @@ impl<'a> Iterator for BlockchainIter<'a> {
 //     }
 // }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{COIN, TestChain};
+
+    #[test]
+    fn validate_transactions_rejects_too_many_sigops() {
+        let chain = TestChain::new("block-sigops");
+        let (lock_a, spend_a) = chain.costly_spend("costly-a", 0, 600);
+        let (lock_b, spend_b) = chain.costly_spend("costly-b", 1, 600);
+        let mut txs = vec![lock_a, spend_a];
+        chain.bc.validate_transactions(&txs).unwrap();
+
+        txs.extend([lock_b, spend_b]);
+        match chain.bc.validate_transactions(&txs) {
+            Err(ChainError::InvalidBlock(reason)) => assert!(reason.contains("signature checks")),
+            other => panic!("expected an invalid block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn time_locks_follow_the_median_time_past() {
+        let chain = TestChain::new("median-time");
+        let mut times: Vec<u64> = chain
+            .bc
+            .iter()
+            .map(|block| (block.get_timestamp() / 1000) as u64)
+            .collect();
+        times.sort();
+        let median_time = chain.bc.get_median_time_past();
+        assert_eq!(median_time, times[times.len() / 2]);
+
+        let mut tx = chain.spend("locked", &[chain.coin(0)], &[COIN - 1_000], 0);
+        for (lock_time, valid) in [(median_time, true), (median_time + 1, false)] {
+            tx.lock_time = lock_time;
+            chain.sign(&mut tx);
+            let result = chain.bc.validate_transactions(std::slice::from_ref(&tx));
+            assert_eq!(result.is_ok(), valid, "lock time {}", lock_time);
+        }
+    }
+
+    #[test]
+    fn relative_locks_count_from_the_spent_output() {
+        let chain = TestChain::new("relative-locks");
+        // the funding outputs confirmed at height 1, the next block is 2
+        for (lock, valid) in [
+            (RelativeLock::Blocks(1), true),
+            (RelativeLock::Blocks(2), false),
+            (RelativeLock::Seconds(512), false),
+        ] {
+            let sequence = lock.to_sequence().unwrap();
+            let tx = chain.spend("locked", &[chain.coin(0)], &[COIN - 1_000], sequence);
+            let result = chain.bc.validate_transactions(&[tx]);
+            assert_eq!(result.is_ok(), valid, "{:?}", lock);
+        }
+    }
+}
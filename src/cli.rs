@@ -1,17 +1,37 @@
 use std::process::exit;
 
+use crate::amount::Amount;
+use crate::block::{Block, TARGET_HEXT};
 use crate::blockchain::Blockchain;
-use crate::error::Result;
-use crate::transaction::Transaction;
-use crate::wallet::Wallets;
-use clap::Command;
+use crate::coinselect::CoinSelection;
+use crate::error::{ChainError, Result};
+use crate::mempool::Mempool;
+use crate::miner::BlockAssembler;
+use crate::mnemonic::Mnemonic;
+use crate::policy::Policy;
+use crate::psbt::Psbt;
+use crate::transaction::{Transaction, TxOptions};
+use crate::tx::RelativeLock;
+use crate::wallet::{
+    address_to_script, hash_pub_key, pub_key_hash_to_address, script_hash_to_address,
+    verify_message, Wallets,
+};
+use crate::walletdir;
+use clap::{ArgAction, ArgMatches, Command};
 use clap::arg;
 
-pub struct Cli {}
+pub struct Cli {
+    policy: Policy,
+    /// Wallet named by `--wallet`, the default wallet if `None`.
+    wallet: Option<String>,
+}
 
 impl Cli {
     pub fn new() -> Result<Cli> {
-        Ok(Cli {})
+        Ok(Cli {
+            policy: Policy::standard(),
+            wallet: None,
+        })
     }
 
     pub fn run(&mut self) -> Result<()> {
@@ -19,53 +39,306 @@ impl Cli {
             .version("0.1.0")
             .author("haxry")
             .about("A simple blockchain implementation in Rust")
-            .subcommand(Command::new("printchain").about("Print all the blocks in the blockchain"))
-            .subcommand(Command::new("createwallet").about("Create a new wallet"))
-            .subcommand(Command::new("listaddresses").about("List all addresses in the wallet"))
+            .arg(
+                arg!(--regtest "'Relax the mempool policy: allow dust, nonstandard scripts and large transactions'")
+                    .global(true),
+            )
+            .arg(
+                arg!(--wallet <NAME> "'The loaded wallet to use instead of the default one'")
+                    .global(true),
+            )
+            .subcommand(
+                Command::new("printchain")
+                    .about("Print all the blocks in the blockchain")
+                    .arg(
+                        arg!(--from <HEIGHT> "'The block height to start listing from'")
+                            .value_parser(clap::value_parser!(usize)),
+                    )
+                    .arg(
+                        arg!(--limit <COUNT> "'The maximum number of blocks to print'")
+                            .value_parser(clap::value_parser!(usize)),
+                    )
+                    .arg(arg!(--reverse "'Print blocks genesis-first'")),
+            )
+            .subcommand(
+                Command::new("createwallet")
+                    .about("Create a new address, or with --name a new named wallet")
+                    .arg(arg!(--name <NAME> "'The name of the wallet to create and load'")),
+            )
+            .subcommand(Command::new("listwallets").about("list the loaded wallets"))
+            .subcommand(
+                Command::new("loadwallet")
+                    .about("load a named wallet so commands can use it with --wallet")
+                    .arg(arg!(<NAME> "'The wallet to load'")),
+            )
+            .subcommand(
+                Command::new("unloadwallet")
+                    .about("unload a named wallet, locking it if it is encrypted")
+                    .arg(arg!(<NAME> "'The wallet to unload'")),
+            )
+            .subcommand(
+                Command::new("listaddresses")
+                    .about("List all addresses in the wallet with their labels")
+                    .arg(arg!(--label <LABEL> "'Only list addresses with this label'")),
+            )
+            .subcommand(
+                Command::new("setlabel")
+                    .about("label one of the wallet's addresses, or clear it with an empty label")
+                    .arg(arg!(<ADDRESS> "'The address to label'"))
+                    .arg(arg!(<LABEL> "'The label'")),
+            )
+            .subcommand(
+                Command::new("addcontact")
+                    .about("add an external address to the address book, to send to by name")
+                    .arg(arg!(<NAME> "'The contact's name'"))
+                    .arg(arg!(<ADDRESS> "'The contact's address'")),
+            )
+            .subcommand(
+                Command::new("removecontact")
+                    .about("remove a contact from the address book")
+                    .arg(arg!(<NAME> "'The contact's name'")),
+            )
+            .subcommand(Command::new("listcontacts").about("list the address book"))
+            .subcommand(
+                Command::new("encryptwallet")
+                    .about("encrypt the wallet's secret keys with a passphrase read from stdin"),
+            )
+            .subcommand(
+                Command::new("walletpassphrase")
+                    .about("unlock the wallet for signing with the passphrase read from stdin; anyone who can read your files can sign until the timeout or walletlock, so keep it short")
+                    .arg(
+                        arg!(<TIMEOUT> "'Seconds to stay unlocked'")
+                            .value_parser(clap::value_parser!(u64)),
+                    ),
+            )
+            .subcommand(Command::new("walletlock").about("lock the wallet right away"))
+            .subcommand(
+                Command::new("showmnemonic")
+                    .about("print the mnemonic backing up every derived key; needs an unlocked wallet"),
+            )
+            .subcommand(
+                Command::new("restorewallet")
+                    .about("derive keys from a mnemonic and rescan the chain for the addresses in use")
+                    .arg(arg!(--mnemonic <WORDS> "'The mnemonic words, quoted as one argument'").required(true))
+                    .arg(arg!(--passphrase "'Read the mnemonic passphrase from stdin'")),
+            )
+            .subcommand(
+                Command::new("dumpprivkey")
+                    .about("print the private key of an address; needs an unlocked wallet")
+                    .arg(arg!(<ADDRESS> "'The address whose key to print'")),
+            )
+            .subcommand(
+                Command::new("importprivkey")
+                    .about("add a private key printed by dumpprivkey")
+                    .arg(arg!(<KEY> "'The encoded private key'"))
+                    .arg(arg!(--rescan "'Scan the chain from the key's birth height for its addresses'"))
+                    .arg(
+                        arg!(--"birth-height" <HEIGHT> "'First block that may pay the key, 0 with --rescan and the next block otherwise'")
+                            .value_parser(clap::value_parser!(usize)),
+                    ),
+            )
+            .subcommand(
+                Command::new("importaddress")
+                    .about("watch an address for balances and history without its key")
+                    .arg(arg!(<ADDRESS> "'The address to watch'"))
+                    .arg(arg!(--rescan "'Scan the chain from the address's birth height'"))
+                    .arg(
+                        arg!(--"birth-height" <HEIGHT> "'First block that may pay the address, 0 with --rescan and the next block otherwise'")
+                            .value_parser(clap::value_parser!(usize)),
+                    ),
+            )
+            .subcommand(
+                Command::new("importpubkey")
+                    .about("watch the address of a hex public key without its secret")
+                    .arg(arg!(<PUBKEY> "'The hex public key to watch'"))
+                    .arg(arg!(--rescan "'Scan the chain from the key's birth height'"))
+                    .arg(
+                        arg!(--"birth-height" <HEIGHT> "'First block that may pay the key, 0 with --rescan and the next block otherwise'")
+                            .value_parser(clap::value_parser!(usize)),
+                    ),
+            )
+            .subcommand(
+                Command::new("signmessage")
+                    .about("sign a message with the key of an address to prove owning it")
+                    .arg(arg!(<ADDRESS> "'The address whose key signs'"))
+                    .arg(arg!(<MESSAGE> "'The message to sign'")),
+            )
+            .subcommand(
+                Command::new("verifymessage")
+                    .about("check a message signature made by signmessage")
+                    .arg(arg!(<ADDRESS> "'The address that signed'"))
+                    .arg(arg!(<SIGNATURE> "'The hex signature'"))
+                    .arg(arg!(<MESSAGE> "'The signed message'")),
+            )
             .subcommand(
                 Command::new("getbalance")
-                    .about("get balance in the blockchain")
-                    .arg(arg!(<ADDRESS>"'The Address it get balance for'")),
+                    .about("get balance in the blockchain, of one address or the whole wallet")
+                    .arg(arg!([ADDRESS]"'The Address it get balance for'")),
+            )
+            .subcommand(
+                Command::new("getwalletinfo")
+                    .about("summarize the wallet: total balance across all addresses, outputs and sync height"),
+            )
+            .subcommand(
+                Command::new("rescanblockchain")
+                    .about("forget what the wallet recorded from START on and scan the chain again up to END")
+                    .arg(arg!([START] "'First block to scan, genesis by default'").value_parser(clap::value_parser!(usize)))
+                    .arg(arg!([END] "'Last block to scan, the tip by default'").value_parser(clap::value_parser!(usize))),
+            )
+            .subcommand(
+                Command::new("listtransactions")
+                    .about("list payments to and from the wallet's addresses, watch-only ones included")
+                    .arg(arg!([ADDRESS] "'Only list payments of this address'")),
             )
             .subcommand(
                 Command::new("create")
                     .about("create a new blockchain")
                     .arg(arg!(<ADDRESS>"'The Address to send genesis block reward to'")),
             )
-            .subcommand(
+            .subcommand(payment_args(
                 Command::new("send")
                     .about("send amount to address")
+                    .arg(arg!(<FROM> "'The address to send from'")),
+            ))
+            .subcommand(payment_args(
+                Command::new("sendtoaddress")
+                    .about("send amount to address, funded from any of the wallet's keys"),
+            ))
+            .subcommand(
+                Command::new("senddata")
+                    .about("anchor hex data on the chain in an unspendable output")
+                    .arg(arg!(<HEX> "'The data to anchor, at most 80 bytes without --regtest'"))
+                    .arg(arg!(--from <FROM> "'The address paying the fee'").required(true))
+                    .arg(
+                        arg!(--fee <FEE> "'The fee to pay the miner'")
+                            .value_parser(parse_amount),
+                    )
+                    .arg(arg!(--"reuse-change" "'Send change back to the paying address instead of a fresh change address'"))
+                    .arg(arg!(--mine "'Mine a block with the mempool right away'")),
+            )
+            .subcommand(
+                Command::new("mine")
+                    .about("mine a block with the transactions in the mempool")
+                    .arg(arg!(<ADDRESS> "'The address to send the block reward to'")),
+            )
+            .subcommand(Command::new("listmempool").about("list unconfirmed transactions"))
+            .subcommand(
+                Command::new("bumpfee")
+                    .about("replace a replaceable mempool transaction with one paying a higher fee")
+                    .arg(arg!(<TXID> "'The transaction to replace'"))
+                    .arg(
+                        arg!(--fee <FEE> "'The new total fee, twice the old one by default'")
+                            .value_parser(parse_amount),
+                    ),
+            )
+            .subcommand(
+                Command::new("getpubkey")
+                    .about("print the public key of a wallet address")
+                    .arg(arg!(<ADDRESS> "'The wallet address'")),
+            )
+            .subcommand(
+                Command::new("createmultisig")
+                    .about("create an M-of-N multisig address")
+                    .arg(
+                        arg!(<REQUIRED> "'The number of signatures required'")
+                            .value_parser(clap::value_parser!(usize)),
+                    )
+                    .arg(
+                        arg!(<KEYS> ... "'Hex public keys or local wallet addresses of the cosigners'"),
+                    ),
+            )
+            .subcommand(
+                Command::new("signrawtransaction")
+                    .about("add signatures from local keys to a hex transaction")
+                    .arg(arg!(<HEX> "'The hex-encoded transaction'")),
+            )
+            .subcommand(lock_args(
+                Command::new("createpsbt")
+                    .about("create an unsigned partially signed transaction")
                     .arg(arg!(<FROM> "'The address to send from'"))
                     .arg(arg!(<TO> "'The address to send to'"))
                     .arg(
-                        arg!(<AMOUNT> "'The amount to send'")
-                            .value_parser(clap::value_parser!(i32)),
+                        arg!(<AMOUNT> "'The amount to send in coins, e.g. 1.25'")
+                            .value_parser(parse_amount),
                     ),
+            ))
+            .subcommand(
+                Command::new("signpsbt")
+                    .about("sign a partially signed transaction with local keys, no chain needed")
+                    .arg(arg!(<PSBT> "'The hex-encoded PSBT'")),
+            )
+            .subcommand(
+                Command::new("combinepsbt")
+                    .about("merge signatures from several copies of a PSBT")
+                    .arg(arg!(<PSBTS> ... "'The hex-encoded PSBTs'")),
+            )
+            .subcommand(
+                Command::new("finalizepsbt")
+                    .about("turn a fully signed PSBT into a raw transaction")
+                    .arg(arg!(<PSBT> "'The hex-encoded PSBT'")),
+            )
+            .subcommand(
+                Command::new("broadcast")
+                    .about("add a fully signed hex transaction to the mempool")
+                    .arg(arg!(<HEX> "'The hex-encoded transaction'")),
             )
             .get_matches();
-        if let Some(ref matches) = matches.subcommand_matches("create") {
-            if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let address = String::from(address);
-                let bc = Blockchain::create_blockchain(address.to_string())?;
-                println!("Success! Created a new blockchain");
-            }
+        if matches.get_flag("regtest") {
+            self.policy = Policy::regtest();
         }
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+        self.wallet = matches.get_one::<String>("wallet").cloned();
+        if let Some(matches) = matches.subcommand_matches("create")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            Blockchain::create_blockchain(address.to_string())?;
+            println!("Success! Created a new blockchain");
+        }
+        if let Some(matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let address = String::from(address);
                 let bc = Blockchain::new()?;
-                let utxos = bc.find_UTXO(&address);
-                //println!("length of utxos: {}", utxos.len());
-                let mut balance = 0;
-                for out in utxos {
-                    println!("{:?}", out);
-                    balance += out.value;
-                }
-                println!("Balance of {}: {}", address, balance);
+                println!("Balance of {}: {}", address, address_balance(&bc, address)?);
+            } else {
+                let (spendable, watch_only) = wallet_balances(&self.synced_wallets()?)?;
+                println!("Balance: {}", spendable);
+                println!("Watch-only balance: {}", watch_only);
             }
         }
+        if matches.subcommand_matches("getwalletinfo").is_some() {
+            let ws = self.synced_wallets()?;
+            let (spendable, watch_only) = wallet_balances(&ws)?;
+            let status = if ws.is_locked() {
+                "locked"
+            } else if ws.is_encrypted() {
+                "unlocked"
+            } else {
+                "unencrypted"
+            };
+            let synced = ws
+                .get_synced_height()
+                .map_or(String::from("none"), |height| height.to_string());
+            println!("Balance:            {}", spendable);
+            println!("Watch-only balance: {}", watch_only);
+            println!("Addresses:          {}", ws.get_all_address().len());
+            println!("Unspent outputs:    {}", ws.get_utxos().count());
+            println!("Transactions:       {}", ws.get_transactions().len());
+            println!("Synced to block:    {}", synced);
+            println!("Encryption:         {}", status);
+        }
+        if let Some(matches) = matches.subcommand_matches("rescanblockchain") {
+            let start = matches.get_one::<usize>("START").copied().unwrap_or(0);
+            let end = matches.get_one::<usize>("END").copied();
+            let bc = Blockchain::new()?;
+            let mut ws = self.open_wallets()?;
+            let found = ws.rescan(&bc, start, end)?;
+            ws.save_all()?;
+            println!("Success! Rescanned from block {}, found {} transaction(s)", start, found);
+        }
+        if let Some(matches) = matches.subcommand_matches("listtransactions") {
+            let address = matches.get_one::<String>("ADDRESS").map(String::as_str);
+            self.listtransactions(address)?;
+        }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
+        if let Some(matches) = matches.subcommand_matches("send") {
             let from = if let Some(from) = matches.get_one::<String>("FROM") {
                 String::from(from)
             } else {
@@ -73,44 +346,762 @@ impl Cli {
                 exit(1)
             };
             let to = if let Some(to) = matches.get_one::<String>("TO") {
-                String::from(to)
+                self.open_wallets()?.resolve_address(to)
             } else {
                 println!("Missing 'TO' address");
                 exit(1)
             };
-            let amount = if let Some(amount) = matches.get_one::<i32>("AMOUNT") {
+            let amount = if let Some(amount) = matches.get_one::<Amount>("AMOUNT") {
                 amount
             } else {
                 println!("Missing 'AMOUNT'");
                 exit(1)
             };
+            let options = tx_options(matches)?;
+            let mut bc = Blockchain::new()?;
+            let mut ws = self.open_wallets()?;
+            let tx = Transaction::new_utxo(&mut ws, &from, &to, *amount, &options, &bc)?;
+            if let Some(txid) = self.submit(tx, &bc)? {
+                println!("Success! Sent {} from {} to {} in {}", amount, from, to, txid);
+                if matches.get_flag("mine") {
+                    let mut mempool = self.open_mempool()?;
+                    self.mine(&from, &mut bc, &mut mempool)?;
+                }
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("sendtoaddress")
+            && let Some(to) = matches.get_one::<String>("TO")
+            && let Some(amount) = matches.get_one::<Amount>("AMOUNT")
+        {
+            let mut ws = self.open_wallets()?;
+            let to = ws.resolve_address(to);
+            let options = tx_options(matches)?;
+            let mut bc = Blockchain::new()?;
+            let tx = Transaction::new_wallet_payment(&mut ws, &to, *amount, &options, &bc)?;
+            if let Some(txid) = self.submit(tx, &bc)? {
+                println!("Success! Sent {} to {} in {}", amount, to, txid);
+                if matches.get_flag("mine") {
+                    let address = ws.create_wallet()?;
+                    ws.save_all()?;
+                    let mut mempool = self.open_mempool()?;
+                    self.mine(&address, &mut bc, &mut mempool)?;
+                }
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("senddata")
+            && let Some(data) = matches.get_one::<String>("HEX")
+            && let Some(from) = matches.get_one::<String>("from")
+        {
+            let data = hex::decode(data).map_err(|e| ChainError::Encoding(e.to_string()))?;
+            // check before funding, which hands out a change address
+            if data.len() > self.policy.max_data_carrier_size {
+                return Err(ChainError::InvalidTx(format!(
+                    "data is {} bytes, the limit is {}",
+                    data.len(),
+                    self.policy.max_data_carrier_size
+                )));
+            }
+            let options = TxOptions {
+                fee: *matches.get_one::<Amount>("fee").unwrap_or(&Amount::ZERO),
+                reuse_change: matches.get_flag("reuse-change"),
+                ..TxOptions::default()
+            };
             let mut bc = Blockchain::new()?;
-            let tx = Transaction::new_UTXO(from.clone(), to.clone(), *amount, &bc)?;
-            bc.add_block(vec![tx])?;
-            println!("Success! Sent {} from {} to {}", amount, from, to);
+            let mut ws = self.open_wallets()?;
+            let tx = Transaction::new_data(&mut ws, from, &data, &options, &bc)?;
+            let mut mempool = self.open_mempool()?;
+            let txid = tx.id.clone();
+            mempool.add(tx, &bc)?;
+            mempool.save()?;
+            println!("Success! Anchored {} bytes in {}", data.len(), txid);
+            if matches.get_flag("mine") {
+                self.mine(from, &mut bc, &mut mempool)?;
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("mine")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let mut bc = Blockchain::new()?;
+            let mut mempool = self.open_mempool()?;
+            self.mine(address, &mut bc, &mut mempool)?;
+        }
+        if matches.subcommand_matches("listmempool").is_some() {
+            let bc = Blockchain::new()?;
+            let mempool = self.open_mempool()?;
+            for tx in mempool.sorted_transactions() {
+                let rbf = if tx.signals_rbf() { " (replaceable)" } else { "" };
+                println!(
+                    "{} fee {} size {}{}",
+                    tx.id,
+                    mempool.fee(&tx, &bc)?,
+                    tx.size()?,
+                    rbf
+                );
+            }
         }
-        if let Some(_) = matches.subcommand_matches("printchain") {
-            self.printchain();
+        if let Some(matches) = matches.subcommand_matches("bumpfee")
+            && let Some(txid) = matches.get_one::<String>("TXID")
+        {
+            let bc = Blockchain::new()?;
+            let ws = self.open_wallets()?;
+            let mut mempool = self.open_mempool()?;
+            let tx = mempool
+                .get(txid)
+                .cloned()
+                .ok_or_else(|| ChainError::TransactionNotFound(txid.to_string()))?;
+            let prev_txs = bc.get_prev_txs_with(&tx, mempool.get_all())?;
+            let old_fee = tx.fee(&prev_txs)?;
+            let fee = match matches.get_one::<Amount>("fee") {
+                Some(fee) => *fee,
+                None => old_fee
+                    .checked_mul(2)
+                    .ok_or_else(|| ChainError::InvalidAmount(old_fee.to_string()))?
+                    .max(Amount::sum([old_fee, Amount::from_base(1)])?),
+            };
+            let replacement = tx.new_replacement(fee, &prev_txs, &ws)?;
+            let new_txid = replacement.id.clone();
+            mempool.add(replacement, &bc)?;
+            mempool.save()?;
+            println!(
+                "Success! Replaced {} (fee {}) with {} (fee {})",
+                txid, old_fee, new_txid, fee
+            );
+        }
+        if let Some(matches) = matches.subcommand_matches("getpubkey")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let ws = self.open_wallets()?;
+            let pub_key = ws
+                .get_public_key(address)
+                .ok_or_else(|| ChainError::WalletNotFound(address.to_string()))?;
+            println!("{}", hex::encode(pub_key));
         }
-        if let Some(_) = matches.subcommand_matches("createwallet") {
-            let mut ws = Wallets::new()?;
-            let address = ws.create_wallet()?;
+        if let Some(matches) = matches.subcommand_matches("createmultisig") {
+            let required = *matches.get_one::<usize>("REQUIRED").unwrap_or(&0);
+            let mut ws = self.open_wallets()?;
+            let mut pub_keys = Vec::new();
+            for key in matches.get_many::<String>("KEYS").into_iter().flatten() {
+                let pub_key = match ws.get_public_key(key) {
+                    Some(pub_key) => pub_key.to_vec(),
+                    None => hex::decode(key)
+                        .map_err(|_| ChainError::InvalidAddress(key.to_string()))?,
+                };
+                pub_keys.push(pub_key);
+            }
+            let address = ws.add_multisig(required, &pub_keys)?;
             ws.save_all()?;
-            println!("Success! Created wallet with address: {}", address);
+            println!("Success! Created multisig with address: {}", address);
         }
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
-            let ws = Wallets::new()?;
-            let addresses = ws.get_all_address();
-            for address in addresses {
+        if let Some(matches) = matches.subcommand_matches("signrawtransaction")
+            && let Some(data) = matches.get_one::<String>("HEX")
+        {
+            let bc = Blockchain::new()?;
+            let ws = self.open_wallets()?;
+            let mempool = self.open_mempool()?;
+            let mut tx = Transaction::from_hex(data)?;
+            tx.sign_with_wallets(&ws, &bc.get_prev_txs_with(&tx, mempool.get_all())?)?;
+            println!("{}", tx.to_hex()?);
+            println!("Complete: {}", bc.verify_transaction(&tx, mempool.get_all())?);
+        }
+        if let Some(matches) = matches.subcommand_matches("createpsbt") {
+            let from = matches.get_one::<String>("FROM").cloned().unwrap_or_default();
+            let to = matches.get_one::<String>("TO").cloned().unwrap_or_default();
+            let amount = *matches.get_one::<Amount>("AMOUNT").unwrap_or(&Amount::ZERO);
+            let bc = Blockchain::new()?;
+            let ws = self.open_wallets()?;
+            let tx = Transaction::new_unsigned_utxo(&from, &to, amount, &lock_options(matches), &bc)?;
+            println!("{}", Psbt::new(tx, &bc, &ws)?.to_hex()?);
+        }
+        if let Some(matches) = matches.subcommand_matches("signpsbt")
+            && let Some(data) = matches.get_one::<String>("PSBT")
+        {
+            let ws = self.open_wallets()?;
+            let mut psbt = Psbt::from_hex(data)?;
+            let signed = psbt.sign_with_wallets(&ws)?;
+            println!("{}", psbt.to_hex()?);
+            println!("Signed {} input(s)", signed);
+        }
+        if let Some(matches) = matches.subcommand_matches("combinepsbt") {
+            let mut psbts = matches.get_many::<String>("PSBTS").into_iter().flatten();
+            if let Some(first) = psbts.next() {
+                let mut combined = Psbt::from_hex(first)?;
+                for data in psbts {
+                    combined.combine(&Psbt::from_hex(data)?)?;
+                }
+                println!("{}", combined.to_hex()?);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("finalizepsbt")
+            && let Some(data) = matches.get_one::<String>("PSBT")
+        {
+            let tx = Psbt::from_hex(data)?.finalize()?;
+            println!("{}", tx.to_hex()?);
+        }
+        if let Some(matches) = matches.subcommand_matches("broadcast")
+            && let Some(data) = matches.get_one::<String>("HEX")
+        {
+            let bc = Blockchain::new()?;
+            let mut mempool = self.open_mempool()?;
+            let tx = Transaction::from_hex(data)?;
+            let txid = tx.id.clone();
+            for replaced in mempool.add(tx, &bc)? {
+                println!("Replaced {}", replaced);
+            }
+            mempool.save()?;
+            println!("Success! Added transaction {} to the mempool", txid);
+        }
+        if let Some(matches) = matches.subcommand_matches("printchain") {
+            let from = matches.get_one::<usize>("from").copied();
+            let limit = matches.get_one::<usize>("limit").copied();
+            let reverse = matches.get_flag("reverse");
+            self.printchain(from, limit, reverse)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("createwallet") {
+            if let Some(name) = matches.get_one::<String>("name") {
+                let mut ws = walletdir::create(name)?;
+                let address = ws.create_wallet()?;
+                ws.save_all()?;
+                println!("Success! Created wallet {} with address: {}", name, address);
+            } else {
+                let mut ws = self.open_wallets()?;
+                let address = ws.create_wallet()?;
+                ws.save_all()?;
+                println!("Success! Created wallet with address: {}", address);
+            }
+        }
+        if matches.subcommand_matches("listwallets").is_some() {
+            for name in walletdir::list_loaded()? {
+                println!("{}", name);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("loadwallet")
+            && let Some(name) = matches.get_one::<String>("NAME")
+        {
+            walletdir::load(name)?;
+            println!("Success! Loaded wallet {}", name);
+        }
+        if let Some(matches) = matches.subcommand_matches("unloadwallet")
+            && let Some(name) = matches.get_one::<String>("NAME")
+        {
+            walletdir::unload(name)?;
+            println!("Success! Unloaded wallet {}", name);
+        }
+        if matches.subcommand_matches("encryptwallet").is_some() {
+            let mut ws = self.open_wallets()?;
+            ws.encrypt(&read_passphrase()?)?;
+            ws.save_all()?;
+            println!("Success! Wallet encrypted, unlock it with walletpassphrase to sign");
+        }
+        if let Some(matches) = matches.subcommand_matches("walletpassphrase")
+            && let Some(timeout) = matches.get_one::<u64>("TIMEOUT")
+        {
+            let mut ws = self.open_wallets()?;
+            ws.unlock(&read_passphrase()?, *timeout)?;
+            println!("Success! Wallet unlocked for {} seconds", timeout);
+        }
+        if matches.subcommand_matches("walletlock").is_some() {
+            let mut ws = self.open_wallets()?;
+            ws.lock()?;
+            println!("Success! Wallet locked");
+        }
+        if matches.subcommand_matches("showmnemonic").is_some() {
+            let ws = self.open_wallets()?;
+            println!("{}", ws.get_mnemonic()?);
+        }
+        if let Some(matches) = matches.subcommand_matches("restorewallet")
+            && let Some(words) = matches.get_one::<String>("mnemonic")
+        {
+            let mnemonic: Mnemonic = words.parse()?;
+            let passphrase = if matches.get_flag("passphrase") {
+                read_passphrase()?
+            } else {
+                String::new()
+            };
+            let mut ws = self.open_wallets()?;
+            ws.restore(&mnemonic, &passphrase)?;
+            let found = match Blockchain::new() {
+                Ok(bc) => ws.rescan(&bc, 0, None)?,
+                Err(ChainError::NotInitialized) => 0,
+                Err(e) => return Err(e),
+            };
+            ws.save_all()?;
+            println!("Success! Restored wallet, found {} transaction(s)", found);
+            for address in ws.get_all_address() {
                 println!("{}", address);
             }
         }
+        if let Some(matches) = matches.subcommand_matches("signmessage")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+            && let Some(message) = matches.get_one::<String>("MESSAGE")
+        {
+            let ws = self.open_wallets()?;
+            println!("{}", ws.sign_message(address, message)?);
+        }
+        if let Some(matches) = matches.subcommand_matches("verifymessage")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+            && let Some(signature) = matches.get_one::<String>("SIGNATURE")
+            && let Some(message) = matches.get_one::<String>("MESSAGE")
+        {
+            println!("{}", verify_message(address, signature, message)?);
+        }
+        if let Some(matches) = matches.subcommand_matches("dumpprivkey")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let ws = self.open_wallets()?;
+            println!("{}", ws.dump_key(address)?);
+        }
+        if let Some(matches) = matches.subcommand_matches("importprivkey")
+            && let Some(key) = matches.get_one::<String>("KEY")
+        {
+            let (bc, birth) = import_birth(matches)?;
+            let mut ws = self.open_wallets()?;
+            let address = ws.import_key(key)?;
+            ws.save_all()?;
+            println!("Success! Imported key of address: {}", address);
+            import_rescan(matches, &mut ws, bc.as_ref(), &address, birth)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("importaddress")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let (bc, birth) = import_birth(matches)?;
+            let mut ws = self.open_wallets()?;
+            ws.watch_address(address)?;
+            ws.save_all()?;
+            println!("Success! Watching address: {}", address);
+            import_rescan(matches, &mut ws, bc.as_ref(), address, birth)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("importpubkey")
+            && let Some(key) = matches.get_one::<String>("PUBKEY")
+        {
+            let pub_key =
+                hex::decode(key).map_err(|_| ChainError::InvalidAddress(key.to_string()))?;
+            let (bc, birth) = import_birth(matches)?;
+            let mut ws = self.open_wallets()?;
+            let address = ws.watch_public_key(&pub_key)?;
+            ws.save_all()?;
+            println!("Success! Watching address: {}", address);
+            import_rescan(matches, &mut ws, bc.as_ref(), &address, birth)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("listaddresses") {
+            let ws = self.open_wallets()?;
+            let filter = matches.get_one::<String>("label");
+            let mut addresses = ws.get_all_address();
+            addresses.sort_by(|a, b| (ws.get_label(a), a).cmp(&(ws.get_label(b), b)));
+            for address in addresses {
+                let label = ws.get_label(&address);
+                if filter.is_some_and(|filter| label != Some(filter.as_str())) {
+                    continue;
+                }
+                let mut line = address.clone();
+                if let Some(label) = label {
+                    line += &format!(" \"{}\"", label);
+                }
+                if ws.is_watch_only(&address) {
+                    line += " (watch-only)";
+                }
+                println!("{}", line);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("setlabel")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+            && let Some(label) = matches.get_one::<String>("LABEL")
+        {
+            let mut ws = self.open_wallets()?;
+            ws.set_label(address, label)?;
+            ws.save_all()?;
+            println!("Success! Labelled {}", address);
+        }
+        if let Some(matches) = matches.subcommand_matches("addcontact")
+            && let Some(name) = matches.get_one::<String>("NAME")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let mut ws = self.open_wallets()?;
+            ws.add_contact(name, address)?;
+            ws.save_all()?;
+            println!("Success! Added contact {}: {}", name, address);
+        }
+        if let Some(matches) = matches.subcommand_matches("removecontact")
+            && let Some(name) = matches.get_one::<String>("NAME")
+        {
+            let mut ws = self.open_wallets()?;
+            ws.remove_contact(name)?;
+            ws.save_all()?;
+            println!("Success! Removed contact {}", name);
+        }
+        if matches.subcommand_matches("listcontacts").is_some() {
+            let ws = self.open_wallets()?;
+            for (name, address) in ws.get_contacts() {
+                println!("{} {}", name, address);
+            }
+        }
+        Ok(())
+    }
+
+    fn open_wallets(&self) -> Result<Wallets> {
+        walletdir::open(self.wallet.as_deref())
+    }
+
+    /// Loads the wallet with every block mined since it was last saved
+    /// connected, saving the result.
+    fn synced_wallets(&self) -> Result<Wallets> {
+        let bc = Blockchain::new()?;
+        let mut ws = self.open_wallets()?;
+        ws.sync(&bc)?;
+        ws.save_all()?;
+        Ok(ws)
+    }
+
+    fn open_mempool(&self) -> Result<Mempool> {
+        let mut mempool = Mempool::new()?;
+        mempool.set_policy(self.policy.clone());
+        Ok(mempool)
+    }
+
+    /// Adds a payment to the mempool and returns its id, or prints it for
+    /// later broadcast if it is still time-locked or needs cosigners.
+    fn submit(&self, tx: Transaction, bc: &Blockchain) -> Result<Option<String>> {
+        let mut mempool = self.open_mempool()?;
+        let height = bc.get_best_height()? + 1;
+        match bc.check_locks(&tx, height, bc.get_median_time_past(), mempool.get_all()) {
+            Err(ChainError::InvalidTx(reason)) => {
+                println!("Transaction {}, broadcast it later:", reason);
+                println!("{}", tx.to_hex()?);
+                return Ok(None);
+            }
+            result => result?,
+        }
+        if !bc.verify_transaction(&tx, mempool.get_all())? {
+            println!("Transaction needs more signatures:");
+            println!("{}", tx.to_hex()?);
+            return Ok(None);
+        }
+        let txid = tx.id.clone();
+        mempool.add(tx, bc)?;
+        mempool.save()?;
+        Ok(Some(txid))
+    }
+
+    /// Mines the best-paying mempool transactions into a block rewarding `address`.
+    fn mine(&self, address: &str, bc: &mut Blockchain, mempool: &mut Mempool) -> Result<()> {
+        let template = BlockAssembler::new(bc, mempool).assemble(address)?;
+        let height = bc.get_best_height()? + 1;
+        let block_txs = template.transactions;
+        bc.add_block(block_txs.clone())?;
+        for tx in &block_txs {
+            mempool.remove(&tx.id);
+        }
+        mempool.save()?;
+        for name in walletdir::list_loaded()? {
+            let mut ws = walletdir::open(Some(&name))?;
+            ws.sync(bc)?;
+            ws.save_all()?;
+        }
+        println!(
+            "Success! Mined block {} with {} transaction(s) paying {} in fees",
+            height,
+            block_txs.len(),
+            template.fees
+        );
         Ok(())
     }
-    fn printchain(&self) {
-        let bc = Blockchain::new().unwrap();
-        for block in bc.iter() {
-            println!("{:?}", block);
+
+    fn printchain(&self, from: Option<usize>, limit: Option<usize>, reverse: bool) -> Result<()> {
+        let bc = Blockchain::new()?;
+        let mut blocks: Vec<Block> = bc.iter().collect();
+        if reverse {
+            blocks.reverse();
+        }
+        let blocks = blocks
+            .into_iter()
+            .filter(|b| match from {
+                Some(h) if reverse => b.get_height() >= h,
+                Some(h) => b.get_height() <= h,
+                None => true,
+            })
+            .take(limit.unwrap_or(usize::MAX));
+        for block in blocks {
+            print_block(&block)?;
         }
+        Ok(())
+    }
+
+    /// Prints, oldest first, every confirmed payment to or from the wallet's
+    /// addresses, or only `address`.
+    fn listtransactions(&self, address: Option<&str>) -> Result<()> {
+        let ws = self.synced_wallets()?;
+        for wtx in ws.get_transactions() {
+            let spent = wtx.spent.iter().map(|out| (out, '-'));
+            let received = wtx.received.iter().map(|out| (out, '+'));
+            for (out, sign) in spent.chain(received) {
+                if address.is_some_and(|address| address != out.address) {
+                    continue;
+                }
+                let watch_only = if ws.is_watch_only(&out.address) {
+                    " (watch-only)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{} {} {} {}{}{}",
+                    wtx.height, wtx.txid, out.address, sign, out.value, watch_only
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds the absolute and relative lock options of a new transaction.
+fn lock_args(command: Command) -> Command {
+    command
+        .arg(
+            arg!(--locktime <LOCKTIME> "'Block height, or unix time from 500000000 on, before which the transaction cannot be mined'")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"relative-lock" <LOCK> "'Blocks, or seconds with an s suffix, the spent outputs must be confirmed for before the transaction can be mined'")
+                .value_parser(parse_relative_lock),
+        )
+}
+
+/// Adds the recipient, amount and funding options shared by `send` and
+/// `sendtoaddress`.
+fn payment_args(command: Command) -> Command {
+    let command = command
+        .arg(arg!(<TO> "'The address or contact name to send to'"))
+        .arg(
+            arg!(<AMOUNT> "'The amount to send in coins, e.g. 1.25'")
+                .value_parser(parse_amount),
+        );
+    lock_args(command)
+        .arg(
+            arg!(--fee <FEE> "'The fee to pay the miner'")
+                .value_parser(parse_amount),
+        )
+        .arg(arg!(--replaceable "'Allow replacing the transaction with a higher fee'"))
+        .arg(
+            arg!(--"coin-selection" <STRATEGY> "'How to choose the outputs to spend'")
+                .value_parser(CoinSelection::NAMES),
+        )
+        .arg(
+            arg!(--utxo <OUTPOINT> "'Spend exactly this txid:vout, may be repeated'")
+                .action(ArgAction::Append)
+                .value_parser(parse_outpoint),
+        )
+        .arg(arg!(--"reuse-change" "'Send change back to the spent address instead of a fresh change address'"))
+        .arg(arg!(--mine "'Mine a block with the mempool right away'"))
+}
+
+/// The options `lock_args` adds, leaving the others at their defaults.
+fn lock_options(matches: &ArgMatches) -> TxOptions {
+    TxOptions {
+        lock_time: *matches.get_one::<u64>("locktime").unwrap_or(&0),
+        relative_lock: matches.get_one::<RelativeLock>("relative-lock").copied(),
+        ..TxOptions::default()
+    }
+}
+
+fn tx_options(matches: &ArgMatches) -> Result<TxOptions> {
+    Ok(TxOptions {
+        fee: *matches.get_one::<Amount>("fee").unwrap_or(&Amount::ZERO),
+        replaceable: matches.get_flag("replaceable"),
+        coin_selection: match matches.get_one::<String>("coin-selection") {
+            Some(name) => name.parse()?,
+            None => CoinSelection::default(),
+        },
+        utxos: matches
+            .get_many::<(String, i32)>("utxo")
+            .map(|utxos| utxos.cloned().collect())
+            .unwrap_or_default(),
+        reuse_change: matches.get_flag("reuse-change"),
+        ..lock_options(matches)
+    })
+}
+
+/// Totals the wallet's unspent outputs, spendable and watch-only apart.
+fn wallet_balances(ws: &Wallets) -> Result<(Amount, Amount)> {
+    let (watch_only, spendable): (Vec<_>, Vec<_>) =
+        ws.get_utxos().partition(|out| ws.is_watch_only(&out.address));
+    Ok((
+        Amount::sum(spendable.iter().map(|out| out.value))?,
+        Amount::sum(watch_only.iter().map(|out| out.value))?,
+    ))
+}
+
+fn address_balance(bc: &Blockchain, address: &str) -> Result<Amount> {
+    let utxos = bc.find_utxo(&address_to_script(address)?);
+    Amount::sum(utxos.iter().map(|out| out.value))
+}
+
+/// Opens the chain, if there is one, and picks the birth height of an
+/// imported key or address: `--birth-height`, else genesis when rescanning,
+/// else the next block since nothing could have paid it yet.
+fn import_birth(matches: &ArgMatches) -> Result<(Option<Blockchain>, usize)> {
+    let bc = match Blockchain::new() {
+        Ok(bc) => Some(bc),
+        Err(ChainError::NotInitialized) => None,
+        Err(e) => return Err(e),
+    };
+    let birth = match matches.get_one::<usize>("birth-height") {
+        Some(height) => *height,
+        None if matches.get_flag("rescan") => 0,
+        None => match &bc {
+            Some(bc) => bc.get_best_height()? + 1,
+            None => 0,
+        },
+    };
+    Ok((bc, birth))
+}
+
+fn import_rescan(
+    matches: &ArgMatches,
+    ws: &mut Wallets,
+    bc: Option<&Blockchain>,
+    address: &str,
+    birth: usize,
+) -> Result<()> {
+    if matches.get_flag("rescan")
+        && let Some(bc) = bc
+    {
+        ws.rescan(bc, birth, None)?;
+        ws.save_all()?;
+        println!(
+            "Rescanned from block {}, balance of {}: {}",
+            birth,
+            address,
+            address_balance(bc, address)?
+        );
+    }
+    Ok(())
+}
+
+fn print_block(block: &Block) -> Result<()> {
+    println!("============ Block {} ============", block.get_height());
+    println!("Hash:       {}", block.get_hash());
+    println!("Prev hash:  {}", block.get_prev_hash());
+    println!("Time:       {}", format_timestamp(block.get_timestamp()));
+    println!("Difficulty: {}", TARGET_HEXT);
+    println!("Nonce:      {}", block.get_nonce());
+    println!("Tx count:   {}", block.get_transaction().len());
+    for tx in block.get_transaction() {
+        print_transaction(tx)?;
+    }
+    println!();
+    Ok(())
+}
+
+fn print_transaction(tx: &Transaction) -> Result<()> {
+    if tx.is_coinbase() {
+        println!("  Tx {} (coinbase)", tx.id);
+    } else {
+        println!("  Tx {}", tx.id);
+        for (index, input) in tx.vin.iter().enumerate() {
+            match input.script_sig.p2pkh_pub_key() {
+                Some(pub_key) => {
+                    let mut pub_key_hash = pub_key.to_vec();
+                    hash_pub_key(&mut pub_key_hash);
+                    println!(
+                        "    in  {}: {}:{} from {}",
+                        index,
+                        input.txid,
+                        input.vout,
+                        pub_key_hash_to_address(&pub_key_hash)?
+                    );
+                }
+                None => println!(
+                    "    in  {}: {}:{} script {}",
+                    index, input.txid, input.vout, input.script_sig
+                ),
+            }
+        }
+    }
+    for (index, output) in tx.vout.iter().enumerate() {
+        let address = match (
+            output.script_pub_key.p2pkh_hash(),
+            output.script_pub_key.p2sh_hash(),
+        ) {
+            (Some(pub_key_hash), _) => Some(pub_key_hash_to_address(pub_key_hash)?),
+            (_, Some(script_hash)) => Some(script_hash_to_address(script_hash)?),
+            _ => None,
+        };
+        match (address, output.script_pub_key.data()) {
+            (Some(address), _) => println!("    out {}: {} to {}", index, output.value, address),
+            (None, Some(data)) => println!("    out {}: data {}", index, hex::encode(data)),
+            (None, None) => println!(
+                "    out {}: {} script {}",
+                index, output.value, output.script_pub_key
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Formats a millisecond unix timestamp as an ISO 8601 UTC string.
+fn format_timestamp(millis: u128) -> String {
+    let secs = (millis / 1000) as i64;
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        millis % 1000
+    )
+}
+
+/// Parses a decimal coin amount such as `1.25` for clap.
+fn parse_amount(s: &str) -> std::result::Result<Amount, String> {
+    s.parse::<Amount>().map_err(|e| e.to_string())
+}
+
+/// Parses a relative lock for clap: a number of blocks, or of seconds with
+/// an `s` suffix.
+fn parse_relative_lock(s: &str) -> std::result::Result<RelativeLock, String> {
+    let invalid = || format!("expected blocks, or seconds ending in 's', got '{}'", s);
+    let lock = match s.strip_suffix('s') {
+        Some(seconds) => RelativeLock::Seconds(seconds.parse().map_err(|_| invalid())?),
+        None => RelativeLock::Blocks(s.parse().map_err(|_| invalid())?),
+    };
+    lock.to_sequence().map_err(|e| e.to_string())?;
+    Ok(lock)
+}
+
+/// Parses a `txid:vout` outpoint for clap.
+fn parse_outpoint(s: &str) -> std::result::Result<(String, i32), String> {
+    let (txid, vout) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected txid:vout, got '{}'", s))?;
+    let vout = vout.parse().map_err(|_| format!("invalid output index '{}'", vout))?;
+    Ok((txid.to_string(), vout))
+}
+
+/// Reads a passphrase from the first line of stdin, so it stays out of the
+/// shell history.
+fn read_passphrase() -> Result<String> {
+    eprint!("Passphrase: ");
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| ChainError::Encoding(e.to_string()))?;
+    let passphrase = line.trim_end_matches(['\r', '\n']).to_string();
+    if passphrase.is_empty() {
+        return Err(ChainError::Wallet(String::from("passphrase cannot be empty")));
     }
+    Ok(passphrase)
 }
@@ -0,0 +1,285 @@
+use crate::amount::Amount;
+use crate::error::{ChainError, Result};
+use crate::tx::TxOutput;
+use rand::seq::SliceRandom;
+use std::str::FromStr;
+
+/// Most branches `BranchAndBound` explores before giving up on an exact match.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// An unspent output a transaction could spend.
+#[derive(Debug, Clone)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: i32,
+    pub output: TxOutput,
+}
+
+/// Chooses which outputs fund a payment of `target`, or `None` if the
+/// candidates cannot cover it.
+pub trait CoinSelector {
+    fn select(&self, utxos: &[Utxo], target: Amount) -> Option<Vec<Utxo>>;
+}
+
+/// Spends the biggest outputs first, using as few inputs as possible.
+pub struct LargestFirst;
+
+/// Spends the smallest outputs first, consolidating dust.
+pub struct SmallestFirst;
+
+/// Searches for a set of outputs matching the target exactly, so no change
+/// output is needed, and falls back on `LargestFirst` if there is none.
+pub struct BranchAndBound;
+
+/// Picks outputs at random until the target is covered, then keeps adding
+/// random outputs while that brings the change closer to the target itself,
+/// leaving change that is useful for later payments.
+pub struct RandomImprove;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, utxos: &[Utxo], target: Amount) -> Option<Vec<Utxo>> {
+        let mut sorted = utxos.to_vec();
+        sorted.sort_by_key(|utxo| std::cmp::Reverse(utxo.output.value));
+        take_until(sorted, target)
+    }
+}
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, utxos: &[Utxo], target: Amount) -> Option<Vec<Utxo>> {
+        let mut sorted = utxos.to_vec();
+        sorted.sort_by_key(|utxo| utxo.output.value);
+        take_until(sorted, target)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, utxos: &[Utxo], target: Amount) -> Option<Vec<Utxo>> {
+        let mut sorted = utxos.to_vec();
+        sorted.sort_by_key(|utxo| std::cmp::Reverse(utxo.output.value));
+        let values: Vec<u64> = sorted.iter().map(|u| u.output.value.to_base()).collect();
+        // remaining[i] is the total of values[i..], the most a branch can still add
+        let mut remaining = vec![0u64; values.len() + 1];
+        for i in (0..values.len()).rev() {
+            remaining[i] = remaining[i + 1].saturating_add(values[i]);
+        }
+
+        let mut chosen = Vec::new();
+        let mut tries = 0;
+        if exact_match(
+            &values,
+            &remaining,
+            target.to_base(),
+            0,
+            &mut chosen,
+            &mut tries,
+        ) {
+            return Some(chosen.into_iter().map(|i| sorted[i].clone()).collect());
+        }
+        LargestFirst.select(utxos, target)
+    }
+}
+
+impl CoinSelector for RandomImprove {
+    fn select(&self, utxos: &[Utxo], target: Amount) -> Option<Vec<Utxo>> {
+        let mut pool = utxos.to_vec();
+        pool.shuffle(&mut rand::thread_rng());
+        let mut selected = Vec::new();
+        let mut total = Amount::ZERO;
+        while total < target {
+            let utxo = pool.pop()?;
+            total = total.checked_add(utxo.output.value)?;
+            selected.push(utxo);
+        }
+
+        // aim for change about the size of the payment, never above twice it
+        let ideal = target.checked_mul(2)?;
+        let limit = target.checked_mul(3)?;
+        while let Some(utxo) = pool.pop() {
+            let Some(improved) = total.checked_add(utxo.output.value) else {
+                break;
+            };
+            if improved > limit || distance(improved, ideal) >= distance(total, ideal) {
+                break;
+            }
+            total = improved;
+            selected.push(utxo);
+        }
+        Some(selected)
+    }
+}
+
+/// The coin selection strategies a payment can ask for by name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoinSelection {
+    LargestFirst,
+    SmallestFirst,
+    #[default]
+    BranchAndBound,
+    RandomImprove,
+}
+
+impl CoinSelection {
+    pub const NAMES: [&'static str; 4] = [
+        "largest-first",
+        "smallest-first",
+        "branch-and-bound",
+        "random-improve",
+    ];
+
+    pub fn selector(self) -> Box<dyn CoinSelector> {
+        match self {
+            CoinSelection::LargestFirst => Box::new(LargestFirst),
+            CoinSelection::SmallestFirst => Box::new(SmallestFirst),
+            CoinSelection::BranchAndBound => Box::new(BranchAndBound),
+            CoinSelection::RandomImprove => Box::new(RandomImprove),
+        }
+    }
+}
+
+impl FromStr for CoinSelection {
+    type Err = ChainError;
+
+    fn from_str(s: &str) -> Result<CoinSelection> {
+        match s {
+            "largest-first" => Ok(CoinSelection::LargestFirst),
+            "smallest-first" => Ok(CoinSelection::SmallestFirst),
+            "branch-and-bound" => Ok(CoinSelection::BranchAndBound),
+            "random-improve" => Ok(CoinSelection::RandomImprove),
+            _ => Err(ChainError::Encoding(format!(
+                "unknown coin selection strategy '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+fn take_until(sorted: Vec<Utxo>, target: Amount) -> Option<Vec<Utxo>> {
+    let mut selected = Vec::new();
+    let mut total = Amount::ZERO;
+    for utxo in sorted {
+        if total >= target {
+            break;
+        }
+        total = total.checked_add(utxo.output.value)?;
+        selected.push(utxo);
+    }
+    if total >= target {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+/// Depth-first search over including or skipping each value, pruning branches
+/// that overshoot or can no longer reach `target`.
+fn exact_match(
+    values: &[u64],
+    remaining: &[u64],
+    target: u64,
+    index: usize,
+    chosen: &mut Vec<usize>,
+    tries: &mut usize,
+) -> bool {
+    *tries += 1;
+    if target == 0 {
+        return true;
+    }
+    if index == values.len() || remaining[index] < target || *tries > BNB_MAX_TRIES {
+        return false;
+    }
+    if values[index] <= target {
+        chosen.push(index);
+        if exact_match(
+            values,
+            remaining,
+            target - values[index],
+            index + 1,
+            chosen,
+            tries,
+        ) {
+            return true;
+        }
+        chosen.pop();
+    }
+    exact_match(values, remaining, target, index + 1, chosen, tries)
+}
+
+fn distance(a: Amount, b: Amount) -> u64 {
+    a.to_base().abs_diff(b.to_base())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Script;
+
+    fn utxos(values: &[u64]) -> Vec<Utxo> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Utxo {
+                txid: format!("tx{}", i),
+                vout: 0,
+                output: TxOutput {
+                    value: Amount::from_base(*value),
+                    script_pub_key: Script::new_p2pkh(&[0; 20]),
+                },
+            })
+            .collect()
+    }
+
+    fn total(selected: &[Utxo]) -> u64 {
+        selected
+            .iter()
+            .map(|utxo| utxo.output.value.to_base())
+            .sum()
+    }
+
+    #[test]
+    fn branch_and_bound_matches_exactly() {
+        let selected = BranchAndBound
+            .select(&utxos(&[8, 5, 4, 2, 1]), Amount::from_base(7))
+            .unwrap();
+        assert_eq!(total(&selected), 7);
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_on_largest_first() {
+        let candidates = utxos(&[10, 6, 6]);
+        let selected = BranchAndBound
+            .select(&candidates, Amount::from_base(7))
+            .unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(total(&selected), 10);
+    }
+
+    #[test]
+    fn random_improve_keeps_change_below_twice_the_target() {
+        let candidates = utxos(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 10, 10, 10, 10]);
+        let target = Amount::from_base(10);
+        for _ in 0..200 {
+            let selected = RandomImprove.select(&candidates, target).unwrap();
+            let total = total(&selected);
+            assert!((10..=30).contains(&total), "selected {}", total);
+        }
+    }
+
+    #[test]
+    fn insufficient_funds_select_nothing() {
+        let candidates = utxos(&[3, 2, 1]);
+        let target = Amount::from_base(7);
+        for name in CoinSelection::NAMES {
+            let selector = name.parse::<CoinSelection>().unwrap().selector();
+            assert!(selector.select(&candidates, target).is_none(), "{}", name);
+        }
+        assert!(LargestFirst.select(&[], Amount::from_base(1)).is_none());
+    }
+
+    #[test]
+    fn parses_strategy_names() {
+        for name in CoinSelection::NAMES {
+            assert!(name.parse::<CoinSelection>().is_ok());
+        }
+        assert!("biggest-first".parse::<CoinSelection>().is_err());
+    }
+}
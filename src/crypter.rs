@@ -0,0 +1,83 @@
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::scrypt::{scrypt, ScryptParams};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+pub const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+/// Settings of the memory-hard scrypt function stretching a passphrase into a
+/// key. The defaults take 32 MiB of memory per derivation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: Vec<u8>,
+}
+
+/// Ciphertext authenticated with ChaCha20-Poly1305.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sealed {
+    nonce: Vec<u8>,
+    data: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+impl KdfParams {
+    /// Default costs with a fresh random salt.
+    pub fn new() -> KdfParams {
+        KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+            salt: random_bytes(SALT_SIZE),
+        }
+    }
+
+    pub fn derive_key(&self, passphrase: &[u8]) -> Vec<u8> {
+        let mut key = vec![0; KEY_SIZE];
+        let params = ScryptParams::new(self.log_n, self.r, self.p);
+        scrypt(passphrase, &self.salt, &params, &mut key);
+        key
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encrypts `plaintext` under `key` with a fresh nonce. `aad` is authenticated
+/// but not stored, so decryption must present the same value.
+pub fn seal(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Sealed {
+    let nonce = random_bytes(NONCE_SIZE);
+    let mut data = vec![0; plaintext.len()];
+    let mut tag = vec![0; TAG_SIZE];
+    ChaCha20Poly1305::new(key, &nonce, aad).encrypt(plaintext, &mut data, &mut tag);
+    Sealed { nonce, data, tag }
+}
+
+/// Decrypts `sealed`, or returns `None` if the key, `aad` or the ciphertext
+/// is wrong.
+pub fn open(key: &[u8], sealed: &Sealed, aad: &[u8]) -> Option<Vec<u8>> {
+    if key.len() != KEY_SIZE || sealed.nonce.len() != NONCE_SIZE || sealed.tag.len() != TAG_SIZE
+    {
+        return None;
+    }
+    let mut plaintext = vec![0; sealed.data.len()];
+    ChaCha20Poly1305::new(key, &sealed.nonce, aad)
+        .decrypt(&sealed.data, &mut plaintext, &sealed.tag)
+        .then_some(plaintext)
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
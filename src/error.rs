@@ -1 +1,104 @@
-pub type Result<T> = std::result::Result<T, failure::Error>;
+use crate::amount::Amount;
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, ChainError>;
+
+#[derive(Debug)]
+pub enum ChainError {
+    Storage(sled::Error),
+    Serialization(bincode::Error),
+    Encoding(String),
+    Clock(std::time::SystemTimeError),
+    InvalidBlock(String),
+    InvalidTx(String),
+    InvalidAddress(String),
+    InvalidAmount(String),
+    TransactionNotFound(String),
+    WalletNotFound(String),
+    WalletLocked,
+    Wallet(String),
+    InsufficientFunds { have: Amount, need: Amount },
+    NotInitialized,
+}
+
+impl ChainError {
+    /// Process exit code the CLI reports for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ChainError::Storage(_) | ChainError::Serialization(_) | ChainError::Encoding(_) => 2,
+            ChainError::Clock(_) => 2,
+            ChainError::NotInitialized => 3,
+            ChainError::WalletNotFound(_)
+            | ChainError::WalletLocked
+            | ChainError::Wallet(_)
+            | ChainError::InvalidAddress(_)
+            | ChainError::InvalidAmount(_) => 4,
+            ChainError::InsufficientFunds { .. } => 5,
+            ChainError::InvalidBlock(_)
+            | ChainError::InvalidTx(_)
+            | ChainError::TransactionNotFound(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Storage(e) => write!(f, "storage error: {}", e),
+            ChainError::Serialization(e) => write!(f, "serialization error: {}", e),
+            ChainError::Encoding(e) => write!(f, "encoding error: {}", e),
+            ChainError::Clock(e) => write!(f, "system clock error: {}", e),
+            ChainError::InvalidBlock(reason) => write!(f, "invalid block: {}", reason),
+            ChainError::InvalidTx(reason) => write!(f, "invalid transaction: {}", reason),
+            ChainError::InvalidAddress(address) => write!(f, "invalid address: {}", address),
+            ChainError::InvalidAmount(amount) => write!(f, "invalid amount: {}", amount),
+            ChainError::TransactionNotFound(id) => write!(f, "transaction not found: {}", id),
+            ChainError::WalletNotFound(address) => write!(f, "wallet not found: {}", address),
+            ChainError::WalletLocked => {
+                write!(f, "wallet is locked, unlock it with `walletpassphrase` first")
+            }
+            ChainError::Wallet(reason) => write!(f, "wallet error: {}", reason),
+            ChainError::InsufficientFunds { have, need } => {
+                write!(f, "not enough balance: have {}, need {}", have, need)
+            }
+            ChainError::NotInitialized => {
+                write!(f, "no blockchain found, create one with `create` first")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChainError::Storage(e) => Some(e),
+            ChainError::Serialization(e) => Some(e),
+            ChainError::Clock(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sled::Error> for ChainError {
+    fn from(e: sled::Error) -> Self {
+        ChainError::Storage(e)
+    }
+}
+
+impl From<bincode::Error> for ChainError {
+    fn from(e: bincode::Error) -> Self {
+        ChainError::Serialization(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for ChainError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        ChainError::Encoding(e.to_string())
+    }
+}
+
+impl From<std::time::SystemTimeError> for ChainError {
+    fn from(e: std::time::SystemTimeError) -> Self {
+        ChainError::Clock(e)
+    }
+}
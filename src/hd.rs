@@ -0,0 +1,133 @@
+use crate::error::{ChainError, Result};
+use crate::wallet::Wallet;
+use crypto::ed25519;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha512;
+
+/// Offset marking a hardened child index. Ed25519 only supports hardened
+/// derivation, so every index is hardened.
+pub const HARDENED: u32 = 0x8000_0000;
+/// Account path under which receiving (`/0'/i'`) and change (`/1'/i'`) keys
+/// are derived.
+pub const ACCOUNT_PATH: &str = "m/44'/0'/0'";
+pub const RECEIVE_CHAIN: u32 = 0;
+pub const CHANGE_CHAIN: u32 = 1;
+/// Unused addresses derived past the last used one, so payments to them
+/// are recognised.
+pub const GAP_LIMIT: u32 = 20;
+
+/// A private key and chain code, per SLIP-0010 for ed25519.
+#[derive(Clone)]
+pub struct ExtendedKey {
+    key: Vec<u8>,
+    chain_code: Vec<u8>,
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> ExtendedKey {
+        let (key, chain_code) = hmac_sha512(b"ed25519 seed", &[seed]);
+        ExtendedKey { key, chain_code }
+    }
+
+    /// Derives hardened child `index`, which must be below `HARDENED`.
+    pub fn child(&self, index: u32) -> ExtendedKey {
+        let index = (index | HARDENED).to_be_bytes();
+        let (key, chain_code) = hmac_sha512(&self.chain_code, &[&[0], &self.key, &index]);
+        ExtendedKey { key, chain_code }
+    }
+
+    /// Derives along a path such as `m/44'/0'/0'/0'/5'`.
+    pub fn derive_path(&self, path: &str) -> Result<ExtendedKey> {
+        let invalid = || ChainError::Wallet(format!("invalid derivation path '{}'", path));
+        let mut parts = path.split('/');
+        if parts.next() != Some("m") {
+            return Err(invalid());
+        }
+        let mut key = self.clone();
+        for part in parts {
+            let index: u32 = part
+                .strip_suffix('\'')
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(|_| invalid())?;
+            if index >= HARDENED {
+                return Err(invalid());
+            }
+            key = key.child(index);
+        }
+        Ok(key)
+    }
+
+    pub fn to_wallet(&self) -> Wallet {
+        let (secret_key, public_key) = ed25519::keypair(&self.key);
+        Wallet {
+            secret_key: secret_key.to_vec(),
+            public_key: public_key.to_vec(),
+        }
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> (Vec<u8>, Vec<u8>) {
+    let mut hmac = Hmac::new(Sha512::new(), key);
+    for part in data {
+        hmac.input(part);
+    }
+    let result = hmac.result();
+    let (left, right) = result.code().split_at(32);
+    (left.to_vec(), right.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SLIP-0010 test vector 1 for ed25519
+    const SEED: &str = "000102030405060708090a0b0c0d0e0f";
+
+    fn check(key: &ExtendedKey, chain_code: &str, private_key: &str, public_key: &str) {
+        assert_eq!(hex::encode(&key.chain_code), chain_code);
+        assert_eq!(hex::encode(&key.key), private_key);
+        // SLIP-0010 prefixes ed25519 public keys with a zero byte
+        assert_eq!(
+            format!("00{}", hex::encode(key.to_wallet().public_key)),
+            public_key
+        );
+    }
+
+    #[test]
+    fn master_key_matches_slip10_vector() {
+        let master = ExtendedKey::master(&hex::decode(SEED).unwrap());
+        check(
+            &master,
+            "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb",
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+            "00a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed",
+        );
+    }
+
+    #[test]
+    fn hardened_child_matches_slip10_vector() {
+        let master = ExtendedKey::master(&hex::decode(SEED).unwrap());
+        let expected = (
+            "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69",
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+            "008c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c",
+        );
+        check(&master.child(0), expected.0, expected.1, expected.2);
+        check(
+            &master.derive_path("m/0'").unwrap(),
+            expected.0,
+            expected.1,
+            expected.2,
+        );
+    }
+
+    #[test]
+    fn rejects_unhardened_and_malformed_paths() {
+        let master = ExtendedKey::master(&hex::decode(SEED).unwrap());
+        for path in ["m/0", "0'/1'", "m/x'", "m/2147483648'"] {
+            assert!(master.derive_path(path).is_err(), "{}", path);
+        }
+    }
+}
@@ -1,7 +1,21 @@
+pub mod amount;
+pub mod base58;
 pub mod block;
 pub mod blockchain;
 pub mod cli;
+pub mod coinselect;
+pub mod crypter;
 pub mod error;
+pub mod hd;
+pub mod mempool;
+pub mod miner;
+pub mod mnemonic;
+pub mod policy;
+pub mod psbt;
+pub mod script;
+#[cfg(test)]
+mod testutil;
 pub mod transaction;
 pub mod tx;
 pub mod wallet;
+pub mod walletdir;
@@ -1,8 +1,10 @@
 use my_chain::cli::Cli;
-use my_chain::error::Result;
+use std::process::exit;
 
-fn main() -> Result<()> {
-    let mut cli = Cli::new().unwrap();
-    cli.run().unwrap();
-    Ok(())
+fn main() {
+    let result = Cli::new().and_then(|mut cli| cli.run());
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        exit(e.exit_code());
+    }
 }
@@ -0,0 +1,420 @@
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::coinselect::Utxo;
+use crate::error::{ChainError, Result};
+use crate::policy::Policy;
+use crate::script::Script;
+use crate::transaction::Transaction;
+use log::info;
+use std::collections::{HashMap, HashSet};
+
+/// Most pool transactions, counting itself, a pool transaction may depend on.
+pub const MAX_ANCESTORS: usize = 25;
+/// Most pool transactions, counting itself, that may depend on a pool transaction.
+pub const MAX_DESCENDANTS: usize = 25;
+
+/// Unconfirmed transactions waiting to be mined, stored in `data/mempool`.
+pub struct Mempool {
+    path: String,
+    txs: HashMap<String, Transaction>,
+    policy: Policy,
+}
+
+impl Mempool {
+    pub fn new() -> Result<Mempool> {
+        Mempool::open("data/mempool")
+    }
+
+    /// Opens the pool stored at `path`, creating it if there is none.
+    pub fn open(path: &str) -> Result<Mempool> {
+        let mut pool = Mempool {
+            path: path.to_string(),
+            txs: HashMap::new(),
+            policy: Policy::default(),
+        };
+        let db = sled::open(path)?;
+        for item in db.into_iter() {
+            let i = item?;
+            let tx: Transaction = bincode::deserialize(&i.1)?;
+            pool.txs.insert(tx.id.clone(), tx);
+        }
+        drop(db);
+        Ok(pool)
+    }
+
+    /// Replaces the relay policy checked by `add`.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&Transaction> {
+        self.txs.get(txid)
+    }
+
+    pub fn get_all(&self) -> &HashMap<String, Transaction> {
+        &self.txs
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Every `(txid, vout)` spent by a transaction in the pool.
+    pub fn spent_outputs(&self) -> HashSet<(String, i32)> {
+        self.txs
+            .values()
+            .flat_map(|tx| tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)))
+            .collect()
+    }
+
+    /// Outputs of pool transactions locked by `script_pub_key` that no other
+    /// pool transaction spends.
+    pub fn unspent_outputs(&self, script_pub_key: &Script) -> Vec<Utxo> {
+        self.unspent_outputs_matching(|script| script == script_pub_key)
+    }
+
+    /// Like `unspent_outputs`, for every locking script `matches` accepts.
+    pub fn unspent_outputs_matching(&self, matches: impl Fn(&Script) -> bool) -> Vec<Utxo> {
+        let spent = self.spent_outputs();
+        let mut unspent = Vec::new();
+        for tx in self.sorted_transactions() {
+            for (index, output) in tx.vout.iter().enumerate() {
+                if matches(&output.script_pub_key)
+                    && !output.script_pub_key.is_unspendable()
+                    && !spent.contains(&(tx.id.clone(), index as i32))
+                {
+                    unspent.push(Utxo {
+                        txid: tx.id.clone(),
+                        vout: index as i32,
+                        output: output.clone(),
+                    });
+                }
+            }
+        }
+        unspent
+    }
+
+    pub fn fee(&self, tx: &Transaction, bc: &Blockchain) -> Result<Amount> {
+        tx.fee(&bc.get_prev_txs_with(tx, &self.txs)?)
+    }
+
+    /// Validates `tx` against the relay policy, the chain and the pool and adds
+    /// it. A transaction conflicting with pool entries replaces them, and their
+    /// descendants, if they all opted in to replacement and it pays a strictly
+    /// higher fee than all of them together, at a higher fee rate than each
+    /// conflict. Returns the ids of the replaced transactions.
+    pub fn add(&mut self, tx: Transaction, bc: &Blockchain) -> Result<Vec<String>> {
+        if tx.is_coinbase() {
+            return Err(ChainError::InvalidTx(String::from(
+                "coinbase transactions cannot enter the mempool",
+            )));
+        }
+        if self.txs.contains_key(&tx.id) {
+            return Err(ChainError::InvalidTx(format!(
+                "{} is already in the mempool",
+                tx.id
+            )));
+        }
+        tx.check_duplicate_inputs()?;
+        self.policy.check(&tx)?;
+
+        let chain_spent = bc.find_spent_outputs();
+        let mut conflicts = HashSet::new();
+        for vin in &tx.vin {
+            if chain_spent.contains(&(vin.txid.clone(), vin.vout)) {
+                return Err(ChainError::InvalidTx(format!(
+                    "{}:{} is already spent",
+                    vin.txid, vin.vout
+                )));
+            }
+            for other in self.txs.values() {
+                if other
+                    .vin
+                    .iter()
+                    .any(|o| o.txid == vin.txid && o.vout == vin.vout)
+                {
+                    conflicts.insert(other.id.clone());
+                }
+            }
+        }
+
+        let fee = self.fee(&tx, bc)?;
+        let mut replaced = Vec::new();
+        if !conflicts.is_empty() {
+            let size = tx.size()?;
+            for id in &conflicts {
+                let conflict = &self.txs[id];
+                if !conflict.signals_rbf() {
+                    return Err(ChainError::InvalidTx(format!(
+                        "{} conflicts with {} which is not replaceable",
+                        tx.id, id
+                    )));
+                }
+                // compare fee per byte without dividing
+                let conflict_fee = self.fee(conflict, bc)?;
+                if fee.to_base() as u128 * conflict.size()? as u128
+                    <= conflict_fee.to_base() as u128 * size as u128
+                {
+                    return Err(ChainError::InvalidTx(format!(
+                        "{} must pay a higher fee rate than {} which it replaces",
+                        tx.id, id
+                    )));
+                }
+            }
+            for id in &conflicts {
+                for descendant in self.descendants(id) {
+                    if !replaced.contains(&descendant) {
+                        replaced.push(descendant);
+                    }
+                }
+            }
+            if tx.vin.iter().any(|vin| replaced.contains(&vin.txid)) {
+                return Err(ChainError::InvalidTx(format!(
+                    "{} spends a transaction it replaces",
+                    tx.id
+                )));
+            }
+            let mut replaced_fees = Vec::new();
+            for id in &replaced {
+                replaced_fees.push(self.fee(&self.txs[id], bc)?);
+            }
+            let replaced_fee = Amount::sum(replaced_fees)?;
+            if fee <= replaced_fee {
+                return Err(ChainError::InvalidTx(format!(
+                    "replacement fee {} must exceed the replaced fee {}",
+                    fee, replaced_fee
+                )));
+            }
+        }
+
+        let ancestors = self.ancestors(&tx);
+        if ancestors.len() + 1 > MAX_ANCESTORS {
+            return Err(ChainError::InvalidTx(format!(
+                "{} has more than {} unconfirmed ancestors",
+                tx.id,
+                MAX_ANCESTORS - 1
+            )));
+        }
+        for id in &ancestors {
+            let descendants = self
+                .descendants(id)
+                .iter()
+                .filter(|d| !replaced.contains(d))
+                .count();
+            if descendants + 1 > MAX_DESCENDANTS {
+                return Err(ChainError::InvalidTx(format!(
+                    "{} would have more than {} unconfirmed descendants",
+                    id,
+                    MAX_DESCENDANTS - 1
+                )));
+            }
+        }
+
+        let prev_txs = bc.get_prev_txs_with(&tx, &self.txs)?;
+        let height = bc.get_best_height()? + 1;
+        let time = bc.get_median_time_past();
+        bc.check_locks(&tx, height, time, &self.txs)?;
+        if !tx.verify(&prev_txs, height, time)? {
+            return Err(ChainError::InvalidTx(format!(
+                "{} failed script verification",
+                tx.id
+            )));
+        }
+
+        for id in &replaced {
+            info!("Replacing {} with {}", id, tx.id);
+            self.txs.remove(id);
+        }
+        self.txs.insert(tx.id.clone(), tx);
+        Ok(replaced)
+    }
+
+    pub fn remove(&mut self, txid: &str) -> Option<Transaction> {
+        self.txs.remove(txid)
+    }
+
+    /// Every pool transaction `tx` spends from, transitively, excluding `tx`.
+    pub fn ancestors(&self, tx: &Transaction) -> Vec<String> {
+        let mut found: Vec<String> = Vec::new();
+        let mut queue: Vec<&Transaction> = vec![tx];
+        while let Some(current) = queue.pop() {
+            for vin in &current.vin {
+                if let Some(parent) = self.txs.get(&vin.txid)
+                    && !found.contains(&parent.id)
+                {
+                    found.push(parent.id.clone());
+                    queue.push(parent);
+                }
+            }
+        }
+        found
+    }
+
+    /// `txid` and every pool transaction spending its outputs, transitively.
+    pub fn descendants(&self, txid: &str) -> Vec<String> {
+        let mut found = vec![txid.to_string()];
+        let mut index = 0;
+        while index < found.len() {
+            for tx in self.txs.values() {
+                if !found.contains(&tx.id) && tx.vin.iter().any(|vin| vin.txid == found[index]) {
+                    found.push(tx.id.clone());
+                }
+            }
+            index += 1;
+        }
+        found
+    }
+
+    /// Every pool transaction, each after the pool transactions it spends.
+    pub fn sorted_transactions(&self) -> Vec<Transaction> {
+        let mut sorted: Vec<Transaction> = Vec::new();
+        let mut added: HashSet<String> = HashSet::new();
+        while sorted.len() < self.txs.len() {
+            let before = sorted.len();
+            for tx in self.txs.values() {
+                let ready = tx
+                    .vin
+                    .iter()
+                    .all(|vin| !self.txs.contains_key(&vin.txid) || added.contains(&vin.txid));
+                if !added.contains(&tx.id) && ready {
+                    added.insert(tx.id.clone());
+                    sorted.push(tx.clone());
+                }
+            }
+            if sorted.len() == before {
+                break;
+            }
+        }
+        sorted
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let db = sled::open(&self.path)?;
+        db.clear()?;
+        for (k, v) in self.txs.iter() {
+            db.insert(k.as_bytes(), bincode::serialize(v)?)?;
+        }
+        db.flush()?;
+        drop(db);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{COIN, TestChain};
+    use crate::tx::{MAX_BIP125_RBF_SEQUENCE, SEQUENCE_FINAL};
+
+    const RBF: u32 = MAX_BIP125_RBF_SEQUENCE;
+
+    fn rejection(result: Result<Vec<String>>) -> String {
+        match result {
+            Err(ChainError::InvalidTx(reason)) => reason,
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replacement_must_pay_a_higher_fee_and_fee_rate() {
+        let mut chain = TestChain::new("rbf-fee");
+        let original = chain.spend("original", &[chain.coin(0)], &[COIN - 10_000], RBF);
+        chain.pool.add(original, &chain.bc).unwrap();
+
+        let cheaper = chain.spend("cheaper", &[chain.coin(0)], &[COIN - 5_000], RBF);
+        rejection(chain.pool.add(cheaper, &chain.bc));
+        let same = chain.spend("same", &[chain.coin(0)], &[COIN - 10_000], RBF);
+        assert!(rejection(chain.pool.add(same, &chain.bc)).contains("must exceed"));
+        // a higher fee spread over twice the bytes is a lower rate
+        let inputs = [chain.coin(0), chain.coin(1)];
+        let bigger = chain.spend("bigger", &inputs, &[2 * COIN - 10_500], RBF);
+        assert!(rejection(chain.pool.add(bigger, &chain.bc)).contains("higher fee rate"));
+        assert!(chain.pool.get("original").is_some());
+
+        let bumped = chain.spend("bumped", &[chain.coin(0)], &[COIN - 20_000], RBF);
+        assert_eq!(chain.pool.add(bumped, &chain.bc).unwrap(), vec!["original"]);
+        assert!(chain.pool.get("original").is_none());
+        assert_eq!(chain.pool.len(), 1);
+    }
+
+    #[test]
+    fn conflicts_must_signal_replaceability() {
+        let mut chain = TestChain::new("rbf-signal");
+        let original = chain.spend(
+            "original",
+            &[chain.coin(0)],
+            &[COIN - 10_000],
+            SEQUENCE_FINAL,
+        );
+        chain.pool.add(original, &chain.bc).unwrap();
+
+        let bumped = chain.spend("bumped", &[chain.coin(0)], &[COIN - 50_000], RBF);
+        assert!(rejection(chain.pool.add(bumped, &chain.bc)).contains("not replaceable"));
+        assert!(chain.pool.get("original").is_some());
+    }
+
+    #[test]
+    fn replacement_pays_for_evicted_descendants() {
+        let mut chain = TestChain::new("rbf-descendants");
+        let parent = chain.spend("parent", &[chain.coin(0)], &[COIN - 10_000], RBF);
+        chain.pool.add(parent, &chain.bc).unwrap();
+        let child = chain.spend("child", &[("parent", 0)], &[COIN - 60_000], RBF);
+        chain.pool.add(child, &chain.bc).unwrap();
+
+        // beats the parent alone, but not the parent and child together
+        let short = chain.spend("short", &[chain.coin(0)], &[COIN - 40_000], RBF);
+        assert!(rejection(chain.pool.add(short, &chain.bc)).contains("must exceed"));
+
+        let bumped = chain.spend("bumped", &[chain.coin(0)], &[COIN - 70_000], RBF);
+        let mut replaced = chain.pool.add(bumped, &chain.bc).unwrap();
+        replaced.sort();
+        assert_eq!(replaced, vec!["child", "parent"]);
+        assert_eq!(chain.pool.len(), 1);
+    }
+
+    #[test]
+    fn rejects_duplicate_inputs() {
+        let mut chain = TestChain::new("duplicate-inputs");
+        let inputs = [chain.coin(0), chain.coin(0)];
+        let tx = chain.spend("twice", &inputs, &[2 * COIN - 10_000], SEQUENCE_FINAL);
+        assert!(rejection(chain.pool.add(tx, &chain.bc)).contains("more than once"));
+        assert!(chain.pool.is_empty());
+    }
+
+    #[test]
+    fn sorted_transactions_put_parents_first() {
+        let mut chain = TestChain::new("sorted");
+        let funding = chain.funding.id.clone();
+        // two chains of descendants, one merging into the other
+        let spends: [(&str, &[(&str, i32)]); 6] = [
+            ("a", &[(&funding, 0)]),
+            ("b", &[("a", 0)]),
+            ("c", &[("b", 0)]),
+            ("d", &[(&funding, 1)]),
+            ("e", &[("d", 0)]),
+            ("f", &[("c", 0), ("e", 0)]),
+        ];
+        let mut value = COIN;
+        for (id, inputs) in spends {
+            value -= 10_000;
+            let total = value * inputs.len() as u64;
+            let tx = chain.spend(id, inputs, &[total], SEQUENCE_FINAL);
+            chain.pool.add(tx, &chain.bc).unwrap();
+        }
+
+        let sorted = chain.pool.sorted_transactions();
+        assert_eq!(sorted.len(), 6);
+        for (index, tx) in sorted.iter().enumerate() {
+            for vin in &tx.vin {
+                if chain.pool.get(&vin.txid).is_some() {
+                    let parent = sorted.iter().position(|p| p.id == vin.txid).unwrap();
+                    assert!(parent < index, "{} comes before its parent", tx.id);
+                }
+            }
+        }
+    }
+}
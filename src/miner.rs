@@ -0,0 +1,230 @@
+use crate::amount::Amount;
+use crate::block::{Block, MAX_BLOCK_SIGOPS, MAX_BLOCK_SIZE};
+use crate::blockchain::Blockchain;
+use crate::error::Result;
+use crate::mempool::Mempool;
+use crate::transaction::Transaction;
+use std::collections::{HashMap, HashSet};
+
+/// Picks mempool transactions for the next block. Each candidate is scored by
+/// the fee rate of its package, the transaction plus its unmined ancestors, so
+/// a high-fee child pulls a low-fee parent in with it.
+pub struct BlockAssembler<'a> {
+    bc: &'a Blockchain,
+    mempool: &'a Mempool,
+}
+
+/// A block's worth of transactions and the fees they pay.
+pub struct BlockTemplate {
+    pub transactions: Vec<Transaction>,
+    pub fees: Amount,
+}
+
+impl<'a> BlockAssembler<'a> {
+    pub fn new(bc: &'a Blockchain, mempool: &'a Mempool) -> BlockAssembler<'a> {
+        BlockAssembler { bc, mempool }
+    }
+
+    /// Builds the next block, coinbase to `address` first, filled by fee rate
+    /// up to the block size and signature-check limits.
+    pub fn assemble(&self, address: &str) -> Result<BlockTemplate> {
+        let height = self.bc.get_best_height()? + 1;
+        let data = format!("Block {}", height);
+        // amounts serialize at a fixed width, so the fees do not change the size
+        let placeholder =
+            Transaction::new_coinbase(address.to_string(), data.clone(), Amount::ZERO)?;
+        let reserved = Block::serialized_size(&[placeholder])?;
+        let mut template =
+            self.select_transactions(MAX_BLOCK_SIZE.saturating_sub(reserved), MAX_BLOCK_SIGOPS)?;
+        let coinbase = Transaction::new_coinbase(address.to_string(), data, template.fees)?;
+        template.transactions.insert(0, coinbase);
+        Ok(template)
+    }
+
+    /// Repeatedly takes the package with the best fee rate that still fits in
+    /// `max_size` bytes and `max_sigops` signature checks, keeping every
+    /// parent ahead of its children.
+    pub fn select_transactions(&self, max_size: usize, max_sigops: usize) -> Result<BlockTemplate> {
+        let pool = self.mempool.get_all();
+        let mut entries: HashMap<String, Entry> = HashMap::new();
+        for tx in pool.values() {
+            let prev_txs = self.bc.get_prev_txs_with(tx, pool)?;
+            entries.insert(
+                tx.id.clone(),
+                Entry {
+                    fee: tx.fee(&prev_txs)?,
+                    size: tx.size()?,
+                    sigops: tx.sigop_count(&prev_txs)?,
+                },
+            );
+        }
+
+        let order = self.mempool.sorted_transactions();
+        let mut selected: HashSet<String> = HashSet::new();
+        let mut template = BlockTemplate {
+            transactions: Vec::new(),
+            fees: Amount::ZERO,
+        };
+        let mut size = 0;
+        let mut sigops = 0;
+        loop {
+            let mut best: Option<(Vec<String>, Entry)> = None;
+            for tx in order.iter().filter(|tx| !selected.contains(&tx.id)) {
+                let mut package: Vec<String> = self
+                    .mempool
+                    .ancestors(tx)
+                    .into_iter()
+                    .filter(|id| !selected.contains(id))
+                    .collect();
+                package.push(tx.id.clone());
+                let mut total = Entry::default();
+                for id in &package {
+                    total.add(&entries[id])?;
+                }
+                if size + total.size > max_size || sigops + total.sigops > max_sigops {
+                    continue;
+                }
+                let better = match &best {
+                    Some((_, best_total)) => total.better_rate_than(best_total),
+                    None => true,
+                };
+                if better {
+                    best = Some((package, total));
+                }
+            }
+            let (package, total) = match best {
+                Some(best) => best,
+                None => break,
+            };
+            size += total.size;
+            sigops += total.sigops;
+            template.fees = Amount::sum([template.fees, total.fee])?;
+            for tx in order.iter().filter(|tx| package.contains(&tx.id)) {
+                selected.insert(tx.id.clone());
+                template.transactions.push(tx.clone());
+            }
+        }
+        Ok(template)
+    }
+}
+
+/// What a transaction, or a package of them, pays and costs.
+#[derive(Default)]
+struct Entry {
+    fee: Amount,
+    size: usize,
+    sigops: usize,
+}
+
+impl Entry {
+    fn add(&mut self, other: &Entry) -> Result<()> {
+        self.fee = Amount::sum([self.fee, other.fee])?;
+        self.size += other.size;
+        self.sigops += other.sigops;
+        Ok(())
+    }
+
+    /// Compares fee per byte without dividing.
+    fn better_rate_than(&self, other: &Entry) -> bool {
+        self.fee.to_base() as u128 * other.size as u128
+            > other.fee.to_base() as u128 * self.size as u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Policy;
+    use crate::testutil::{ADDRESS, COIN, TestChain};
+    use crate::tx::SEQUENCE_FINAL;
+
+    fn ids(template: &BlockTemplate) -> Vec<&str> {
+        template
+            .transactions
+            .iter()
+            .map(|tx| tx.id.as_str())
+            .collect()
+    }
+
+    /// A parent paying almost nothing, its child paying a lot and an
+    /// unrelated transaction paying in between.
+    fn cpfp_pool(chain: &mut TestChain) {
+        let parent = chain.spend("parent", &[chain.coin(0)], &[COIN - 1_000], SEQUENCE_FINAL);
+        chain.pool.add(parent, &chain.bc).unwrap();
+        let child = chain.spend("child", &[("parent", 0)], &[COIN - 101_000], SEQUENCE_FINAL);
+        chain.pool.add(child, &chain.bc).unwrap();
+        let other = chain.spend("other", &[chain.coin(1)], &[COIN - 20_000], SEQUENCE_FINAL);
+        chain.pool.add(other, &chain.bc).unwrap();
+    }
+
+    #[test]
+    fn child_pays_for_its_parent() {
+        let mut chain = TestChain::new("miner-cpfp");
+        cpfp_pool(&mut chain);
+        let assembler = BlockAssembler::new(&chain.bc, &chain.pool);
+
+        let template = assembler
+            .select_transactions(MAX_BLOCK_SIZE, MAX_BLOCK_SIGOPS)
+            .unwrap();
+        assert_eq!(ids(&template), vec!["parent", "child", "other"]);
+        assert_eq!(template.fees, Amount::from_base(121_000));
+
+        // room for two transactions goes to the package, not the middle fee
+        let package_size = ["parent", "child"]
+            .iter()
+            .map(|id| chain.pool.get(id).unwrap().size().unwrap())
+            .sum();
+        let template = assembler
+            .select_transactions(package_size, MAX_BLOCK_SIGOPS)
+            .unwrap();
+        assert_eq!(ids(&template), vec!["parent", "child"]);
+    }
+
+    #[test]
+    fn assemble_stops_at_the_block_size_limit() {
+        let mut chain = TestChain::new("miner-size");
+        chain.pool.set_policy(Policy::regtest());
+        // two transactions of over half a block each
+        for (id, vout) in [("big-a", 0), ("big-b", 1)] {
+            let tx = chain.spend(id, &[chain.coin(vout)], &[10_000; 9_000], SEQUENCE_FINAL);
+            assert!(tx.size().unwrap() > MAX_BLOCK_SIZE / 2);
+            chain.pool.add(tx, &chain.bc).unwrap();
+        }
+        let small = chain.spend("small", &[chain.coin(2)], &[COIN - 1_000], SEQUENCE_FINAL);
+        chain.pool.add(small, &chain.bc).unwrap();
+
+        let template = BlockAssembler::new(&chain.bc, &chain.pool)
+            .assemble(ADDRESS)
+            .unwrap();
+        let ids = ids(&template);
+        assert_eq!(ids.len(), 3);
+        assert!(template.transactions[0].is_coinbase());
+        assert!(ids[1] == "big-a" || ids[1] == "big-b");
+        assert_eq!(ids[2], "small");
+        assert!(Block::serialized_size(&template.transactions).unwrap() <= MAX_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn assemble_stops_at_the_sigop_limit() {
+        let mut chain = TestChain::new("miner-sigops");
+        // each spend runs 12,000 signature checks, so only one fits
+        for (id, vout) in [("costly-a", 0), ("costly-b", 1)] {
+            let (lock, spend) = chain.costly_spend(id, vout, 600);
+            chain.pool.add(lock, &chain.bc).unwrap();
+            chain.pool.add(spend, &chain.bc).unwrap();
+        }
+
+        let template = BlockAssembler::new(&chain.bc, &chain.pool)
+            .assemble(ADDRESS)
+            .unwrap();
+        let ids = ids(&template);
+        let costly = ids.iter().filter(|id| id.starts_with("costly")).count();
+        // both locks, but only one spend
+        assert_eq!(costly, 3);
+        assert!(ids.contains(&"costly-a-lock") && ids.contains(&"costly-b-lock"));
+        chain
+            .bc
+            .validate_transactions(&template.transactions)
+            .unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use crate::crypter;
+use crate::error::{ChainError, Result};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::{Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
+
+/// The 2048-word English list of BIP39, one word per line in sorted order.
+const WORDLIST: &str = include_str!("english.txt");
+const BITS_PER_WORD: usize = 11;
+const PBKDF2_ROUNDS: u32 = 2048;
+pub const SEED_SIZE: usize = 64;
+/// Words in a new mnemonic, encoding 256 bits of entropy.
+pub const DEFAULT_WORDS: usize = 24;
+
+/// A BIP39 mnemonic: entropy plus a checksum of its SHA256, written as
+/// words from the English list so a seed can be backed up on paper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mnemonic {
+    entropy: Vec<u8>,
+}
+
+impl Mnemonic {
+    /// A mnemonic of `words` words over fresh random entropy.
+    pub fn generate(words: usize) -> Result<Mnemonic> {
+        Mnemonic::from_entropy(&crypter::random_bytes(words * BITS_PER_WORD * 32 / 33 / 8))
+    }
+
+    /// Accepts 16 to 32 bytes of entropy in steps of 4, i.e. 12 to 24 words.
+    pub fn from_entropy(entropy: &[u8]) -> Result<Mnemonic> {
+        if !(16..=32).contains(&entropy.len()) || !entropy.len().is_multiple_of(4) {
+            return Err(ChainError::Wallet(format!(
+                "mnemonic entropy must be 16 to 32 bytes in steps of 4, not {}",
+                entropy.len()
+            )));
+        }
+        Ok(Mnemonic {
+            entropy: entropy.to_vec(),
+        })
+    }
+
+    pub fn words(&self) -> Vec<&'static str> {
+        let words: Vec<&str> = WORDLIST.lines().collect();
+        let mut bits = self.entropy.clone();
+        bits.push(checksum(&self.entropy));
+        let count = (self.entropy.len() * 8 + self.entropy.len() / 4) / BITS_PER_WORD;
+        (0..count)
+            .map(|i| words[read_bits(&bits, i * BITS_PER_WORD, BITS_PER_WORD)])
+            .collect()
+    }
+
+    /// Stretches the mnemonic and an optional `passphrase` into a wallet
+    /// seed with PBKDF2-HMAC-SHA512. The passphrase is used as given, without
+    /// the Unicode normalization BIP39 applies, so keep it ASCII for other
+    /// wallets to derive the same seed.
+    pub fn to_seed(&self, passphrase: &str) -> Vec<u8> {
+        let mut mac = Hmac::new(Sha512::new(), self.to_string().as_bytes());
+        let salt = format!("mnemonic{}", passphrase);
+        let mut seed = vec![0; SEED_SIZE];
+        pbkdf2(&mut mac, salt.as_bytes(), PBKDF2_ROUNDS, &mut seed);
+        seed
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.words().join(" "))
+    }
+}
+
+impl FromStr for Mnemonic {
+    type Err = ChainError;
+
+    /// Parses space-separated words, rejecting unknown words and phrases
+    /// whose checksum does not match, e.g. from a mistyped backup.
+    fn from_str(s: &str) -> Result<Mnemonic> {
+        let words: Vec<&str> = WORDLIST.lines().collect();
+        let phrase: Vec<&str> = s.split_whitespace().collect();
+        if !matches!(phrase.len(), 12 | 15 | 18 | 21 | 24) {
+            return Err(ChainError::Wallet(format!(
+                "a mnemonic has 12, 15, 18, 21 or 24 words, not {}",
+                phrase.len()
+            )));
+        }
+        let mut bits = vec![0u8; (phrase.len() * BITS_PER_WORD).div_ceil(8)];
+        for (i, word) in phrase.iter().enumerate() {
+            let index = words
+                .binary_search(&word.to_lowercase().as_str())
+                .map_err(|_| ChainError::Wallet(format!("'{}' is not a mnemonic word", word)))?;
+            write_bits(&mut bits, i * BITS_PER_WORD, BITS_PER_WORD, index);
+        }
+        let entropy_len = phrase.len() * BITS_PER_WORD * 32 / 33 / 8;
+        let mnemonic = Mnemonic::from_entropy(&bits[..entropy_len])?;
+        let checksum_bits = entropy_len / 4;
+        if read_bits(&bits, entropy_len * 8, checksum_bits)
+            != read_bits(&[checksum(&mnemonic.entropy)], 0, checksum_bits)
+        {
+            return Err(ChainError::Wallet(String::from(
+                "mnemonic checksum does not match",
+            )));
+        }
+        Ok(mnemonic)
+    }
+}
+
+/// First byte of SHA256(entropy); BIP39 uses its leading `len / 4` bits.
+fn checksum(entropy: &[u8]) -> u8 {
+    let mut hasher = Sha256::new();
+    hasher.input(entropy);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    hash[0]
+}
+
+/// Reads `len` bits starting at bit `start`, most significant first.
+fn read_bits(bytes: &[u8], start: usize, len: usize) -> usize {
+    (start..start + len).fold(0, |value, bit| {
+        (value << 1) | ((bytes[bit / 8] >> (7 - bit % 8)) & 1) as usize
+    })
+}
+
+fn write_bits(bytes: &mut [u8], start: usize, len: usize, value: usize) {
+    for i in 0..len {
+        if (value >> (len - 1 - i)) & 1 == 1 {
+            let bit = start + i;
+            bytes[bit / 8] |= 1 << (7 - bit % 8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BIP39 reference vectors, seeds derived with the passphrase "TREZOR"
+    const VECTORS: [(&str, &str, &str); 3] = [
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+            "dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e1613912f0a5b694407be899ffd31ed3992c456cdf60f5d4564b8ba3f05a69890ad",
+        ),
+    ];
+
+    #[test]
+    fn encodes_entropy_as_reference_words() {
+        for (entropy, words, _) in VECTORS {
+            let mnemonic = Mnemonic::from_entropy(&hex::decode(entropy).unwrap()).unwrap();
+            assert_eq!(mnemonic.to_string(), words);
+        }
+    }
+
+    #[test]
+    fn decodes_reference_words() {
+        for (entropy, words, _) in VECTORS {
+            let mnemonic: Mnemonic = words.parse().unwrap();
+            assert_eq!(hex::encode(&mnemonic.entropy), entropy);
+        }
+    }
+
+    #[test]
+    fn stretches_reference_seeds() {
+        for (_, words, seed) in VECTORS {
+            let mnemonic: Mnemonic = words.parse().unwrap();
+            assert_eq!(hex::encode(mnemonic.to_seed("TREZOR")), seed);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_checksum_and_unknown_words() {
+        let wrong_checksum = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon";
+        assert!(wrong_checksum.parse::<Mnemonic>().is_err());
+        let unknown_word = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abut";
+        assert!(unknown_word.parse::<Mnemonic>().is_err());
+    }
+}
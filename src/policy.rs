@@ -0,0 +1,148 @@
+use crate::amount::Amount;
+use crate::error::{ChainError, Result};
+use crate::script::Script;
+use crate::transaction::Transaction;
+
+/// Outputs worth less than this cost more to spend than they are worth.
+pub const DUST_THRESHOLD: Amount = Amount::from_base(546);
+pub const MAX_STANDARD_TX_SIZE: usize = 100_000;
+pub const MAX_STANDARD_INPUTS: usize = 500;
+pub const MAX_STANDARD_OUTPUTS: usize = 500;
+/// Largest payload of a data-carrier output relayed.
+pub const MAX_DATA_CARRIER_SIZE: usize = 80;
+
+/// Relay rules the mempool applies on top of consensus. A transaction breaking
+/// them is still valid in a block; this node just will not accept it unmined.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub dust_threshold: Amount,
+    pub max_tx_size: usize,
+    pub max_inputs: usize,
+    pub max_outputs: usize,
+    pub max_data_carrier_size: usize,
+    /// Only relay outputs locked by pay-to-pub-key-hash, pay-to-script-hash
+    /// or data-carrier scripts, with at most one data carrier.
+    pub require_standard: bool,
+}
+
+impl Policy {
+    pub fn standard() -> Policy {
+        Policy {
+            dust_threshold: DUST_THRESHOLD,
+            max_tx_size: MAX_STANDARD_TX_SIZE,
+            max_inputs: MAX_STANDARD_INPUTS,
+            max_outputs: MAX_STANDARD_OUTPUTS,
+            max_data_carrier_size: MAX_DATA_CARRIER_SIZE,
+            require_standard: true,
+        }
+    }
+
+    /// Accepts anything consensus accepts, for testing.
+    pub fn regtest() -> Policy {
+        Policy {
+            dust_threshold: Amount::ZERO,
+            max_tx_size: usize::MAX,
+            max_inputs: usize::MAX,
+            max_outputs: usize::MAX,
+            max_data_carrier_size: usize::MAX,
+            require_standard: false,
+        }
+    }
+
+    pub fn check(&self, tx: &Transaction) -> Result<()> {
+        let nonstandard = |reason: String| {
+            Err(ChainError::InvalidTx(format!(
+                "{} is nonstandard: {}",
+                tx.id, reason
+            )))
+        };
+        let size = tx.size()?;
+        if size > self.max_tx_size {
+            return nonstandard(format!("{} bytes, the limit is {}", size, self.max_tx_size));
+        }
+        if tx.vin.len() > self.max_inputs {
+            return nonstandard(format!(
+                "{} inputs, the limit is {}",
+                tx.vin.len(),
+                self.max_inputs
+            ));
+        }
+        if tx.vout.len() > self.max_outputs {
+            return nonstandard(format!(
+                "{} outputs, the limit is {}",
+                tx.vout.len(),
+                self.max_outputs
+            ));
+        }
+        let data_outputs = tx
+            .vout
+            .iter()
+            .filter(|out| out.script_pub_key.data().is_some())
+            .count();
+        if self.require_standard && data_outputs > 1 {
+            return nonstandard(format!("{} data outputs, the limit is 1", data_outputs));
+        }
+        for (index, out) in tx.vout.iter().enumerate() {
+            if self.require_standard && !is_standard(&out.script_pub_key) {
+                return nonstandard(format!("output {} has a nonstandard script", index));
+            }
+            if let Some(data) = out.script_pub_key.data()
+                && data.len() > self.max_data_carrier_size
+            {
+                return nonstandard(format!(
+                    "output {} carries {} bytes of data, the limit is {}",
+                    index,
+                    data.len(),
+                    self.max_data_carrier_size
+                ));
+            }
+            if out.script_pub_key.data().is_none() && out.value < self.dust_threshold {
+                return nonstandard(format!(
+                    "output {} of {} is below the dust threshold {}",
+                    index, out.value, self.dust_threshold
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::standard()
+    }
+}
+
+fn is_standard(script: &Script) -> bool {
+    script.p2pkh_hash().is_some() || script.p2sh_hash().is_some() || script.data().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::TxOutput;
+
+    fn carrying(data: &[&[u8]]) -> Transaction {
+        Transaction {
+            id: String::from("data"),
+            vin: Vec::new(),
+            vout: data.iter().map(|data| TxOutput::new_data(data)).collect(),
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn limits_data_carriers_unless_regtest() {
+        let standard = Policy::standard();
+        let regtest = Policy::regtest();
+        let full = [0; MAX_DATA_CARRIER_SIZE];
+        let over = [0; MAX_DATA_CARRIER_SIZE + 1];
+        for tx in [carrying(&[&full]), carrying(&[&[]])] {
+            standard.check(&tx).unwrap();
+        }
+        for tx in [carrying(&[&over]), carrying(&[b"a", b"b"])] {
+            assert!(standard.check(&tx).is_err());
+            regtest.check(&tx).unwrap();
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use crate::blockchain::Blockchain;
+use crate::error::{ChainError, Result};
+use crate::script::{self, Script};
+use crate::mempool::Mempool;
+use crate::transaction::Transaction;
+use crate::tx::TxOutput;
+use crate::wallet::{hash_pub_key, Wallets};
+use crypto::ed25519;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A partially signed transaction: the unsigned transaction plus everything a
+/// signer needs without access to the chain, and the signatures collected so far.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Psbt {
+    pub tx: Transaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PsbtInput {
+    pub prev_output: TxOutput,
+    pub redeem_script: Option<Script>,
+    /// Signatures keyed by the public key that made them.
+    pub signatures: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Psbt {
+    /// Wraps `tx`, looking up the outputs it spends and any known redeem scripts.
+    pub fn new(tx: Transaction, bc: &Blockchain, wallets: &Wallets) -> Result<Psbt> {
+        // the inputs may spend unconfirmed outputs
+        let prev_txs = bc.get_prev_txs_with(&tx, Mempool::new()?.get_all())?;
+        let mut inputs = Vec::new();
+        for vin in &tx.vin {
+            let prev_output = prev_txs[&vin.txid]
+                .vout
+                .get(vin.vout as usize)
+                .cloned()
+                .ok_or_else(|| {
+                    ChainError::InvalidTx(format!(
+                        "output {}:{} does not exist",
+                        vin.txid, vin.vout
+                    ))
+                })?;
+            inputs.push(PsbtInput {
+                prev_output,
+                redeem_script: None,
+                signatures: BTreeMap::new(),
+            });
+        }
+        let mut psbt = Psbt { tx, inputs };
+        psbt.fill_redeem_scripts(wallets)?;
+        Ok(psbt)
+    }
+
+    /// Signs every input `private_key` can sign for and returns how many it signed.
+    pub fn sign(&mut self, private_key: &[u8]) -> Result<usize> {
+        // ed25519 secret keys carry the public key in their last 32 bytes
+        let pub_key = private_key[32..].to_vec();
+        let mut pub_key_hash = pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        let mut signed = 0;
+        for in_id in 0..self.inputs.len() {
+            let input = &self.inputs[in_id];
+            let can_sign = input.prev_output.is_locked_with_key(&pub_key_hash)
+                || match (&input.redeem_script, input.prev_output.script_pub_key.p2sh_hash()) {
+                    (Some(redeem_script), Some(_)) => redeem_script
+                        .multisig_keys()
+                        .is_some_and(|(_, keys)| keys.contains(&pub_key)),
+                    _ => false,
+                };
+            if !can_sign {
+                continue;
+            }
+            let sighash = self
+                .tx
+                .signature_hash(in_id, &input.prev_output.script_pub_key)?;
+            let signature = ed25519::signature(&sighash, private_key).to_vec();
+            self.inputs[in_id]
+                .signatures
+                .insert(pub_key.clone(), signature);
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    pub fn sign_with_wallets(&mut self, wallets: &Wallets) -> Result<usize> {
+        self.fill_redeem_scripts(wallets)?;
+        let mut signed = 0;
+        wallets.check_unlocked()?;
+        for wallet in wallets.get_wallets() {
+            signed += self.sign(&wallet.secret_key)?;
+        }
+        Ok(signed)
+    }
+
+    /// Merges the signatures and redeem scripts of another copy of the same
+    /// transaction, rejecting a redeem script that does not match the output
+    /// spent and signatures that are not valid signatures of its signers.
+    pub fn combine(&mut self, other: &Psbt) -> Result<()> {
+        if self.tx.id != other.tx.id || self.inputs.len() != other.inputs.len() {
+            return Err(ChainError::InvalidTx(String::from(
+                "cannot combine PSBTs of different transactions",
+            )));
+        }
+        let mut inputs = Vec::new();
+        for (in_id, (input, other)) in self.inputs.iter().zip(&other.inputs).enumerate() {
+            if input.prev_output.script_pub_key != other.prev_output.script_pub_key
+                || input.prev_output.value != other.prev_output.value
+            {
+                return Err(ChainError::InvalidTx(format!(
+                    "input {} spends a different output in the other PSBT",
+                    in_id
+                )));
+            }
+            let mut input = input.clone();
+            if input.redeem_script.is_none() && other.redeem_script.is_some() {
+                input.redeem_script = other.redeem_script.clone();
+                input.check_redeem_script(in_id)?;
+            }
+            for (pub_key, signature) in &other.signatures {
+                if !input.signatures.contains_key(pub_key) {
+                    self.check_signature(in_id, &input, pub_key, signature)?;
+                    input.signatures.insert(pub_key.clone(), signature.clone());
+                }
+            }
+            inputs.push(input);
+        }
+        self.inputs = inputs;
+        Ok(())
+    }
+
+    /// Builds the unlocking scripts from the collected signatures.
+    pub fn finalize(&self) -> Result<Transaction> {
+        let mut tx = self.tx.clone();
+        for (in_id, input) in self.inputs.iter().enumerate() {
+            let script_pub_key = &input.prev_output.script_pub_key;
+            let missing = || ChainError::InvalidTx(format!("input {} is missing signatures", in_id));
+            tx.vin[in_id].script_sig = if let Some(pub_key_hash) = script_pub_key.p2pkh_hash() {
+                let (pub_key, signature) = input
+                    .signatures
+                    .iter()
+                    .find(|(pub_key, _)| {
+                        let mut hash = pub_key.to_vec();
+                        hash_pub_key(&mut hash);
+                        hash == pub_key_hash
+                    })
+                    .ok_or_else(missing)?;
+                self.check_signature(in_id, input, pub_key, signature)?;
+                Script::new_p2pkh_sig(signature, pub_key)
+            } else if let Some(redeem_script) = &input.redeem_script {
+                input.check_redeem_script(in_id)?;
+                let (required, _) = redeem_script.multisig_keys().ok_or_else(missing)?;
+                let sighash = self.tx.signature_hash(in_id, script_pub_key)?;
+                let signatures: Vec<Vec<u8>> = input.signatures.values().cloned().collect();
+                let script_sig = Script::new_multisig_sig(&sighash, &signatures, redeem_script)?;
+                if script_sig.0.len() <= required {
+                    return Err(missing());
+                }
+                script_sig
+            } else {
+                return Err(missing());
+            };
+        }
+        Ok(tx)
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(bincode::serialize(self)?))
+    }
+
+    pub fn from_hex(data: &str) -> Result<Psbt> {
+        let bytes = hex::decode(data).map_err(|e| ChainError::Encoding(e.to_string()))?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    /// Errors unless `pub_key` can sign input `in_id` and `signature` is its
+    /// valid signature.
+    fn check_signature(
+        &self,
+        in_id: usize,
+        input: &PsbtInput,
+        pub_key: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        let script_pub_key = &input.prev_output.script_pub_key;
+        let is_signer = match (script_pub_key.p2pkh_hash(), &input.redeem_script) {
+            (Some(pub_key_hash), _) => {
+                let mut hash = pub_key.to_vec();
+                hash_pub_key(&mut hash);
+                hash == pub_key_hash
+            }
+            (None, Some(redeem_script)) => redeem_script
+                .multisig_keys()
+                .is_some_and(|(_, keys)| keys.iter().any(|key| key == pub_key)),
+            (None, None) => false,
+        };
+        if !is_signer {
+            return Err(ChainError::InvalidTx(format!(
+                "{} is not a signer of input {}",
+                hex::encode(pub_key),
+                in_id
+            )));
+        }
+        let sighash = self.tx.signature_hash(in_id, script_pub_key)?;
+        if !script::verify_signature(&sighash, pub_key, signature) {
+            return Err(ChainError::InvalidTx(format!(
+                "input {} has an invalid signature by {}",
+                in_id,
+                hex::encode(pub_key)
+            )));
+        }
+        Ok(())
+    }
+
+    fn fill_redeem_scripts(&mut self, wallets: &Wallets) -> Result<()> {
+        for input in &mut self.inputs {
+            if input.redeem_script.is_some() {
+                continue;
+            }
+            if let Some(script_hash) = input.prev_output.script_pub_key.p2sh_hash() {
+                for redeem_script in wallets.get_multisigs() {
+                    if redeem_script.script_hash()? == script_hash {
+                        input.redeem_script = Some(redeem_script.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PsbtInput {
+    /// Errors unless the redeem script hashes to the P2SH output spent.
+    fn check_redeem_script(&self, in_id: usize) -> Result<()> {
+        let matches = match (&self.redeem_script, self.prev_output.script_pub_key.p2sh_hash()) {
+            (Some(redeem_script), Some(script_hash)) => redeem_script.script_hash()? == script_hash,
+            (None, _) => true,
+            (Some(_), None) => false,
+        };
+        if !matches {
+            return Err(ChainError::InvalidTx(format!(
+                "the redeem script of input {} does not match the output it spends",
+                in_id
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::tx::{SEQUENCE_FINAL, TxInput};
+
+    /// A PSBT spending one output locked by `script_pub_key`.
+    fn psbt(script_pub_key: Script, redeem_script: Option<Script>) -> Psbt {
+        let tx = Transaction {
+            id: String::from("spend"),
+            vin: vec![TxInput::new(String::from("prev"), 0, SEQUENCE_FINAL)],
+            vout: Vec::new(),
+            lock_time: 0,
+        };
+        Psbt {
+            tx,
+            inputs: vec![PsbtInput {
+                prev_output: TxOutput {
+                    value: Amount::from_base(1_000),
+                    script_pub_key,
+                },
+                redeem_script,
+                signatures: BTreeMap::new(),
+            }],
+        }
+    }
+
+    fn keypair(seed: u8) -> (Vec<u8>, Vec<u8>) {
+        let (secret_key, public_key) = ed25519::keypair(&[seed; 32]);
+        (secret_key.to_vec(), public_key.to_vec())
+    }
+
+    /// A PSBT spending a 2-of-2 P2SH output of keys 1 and 2.
+    fn multisig_psbt() -> Psbt {
+        let keys = vec![keypair(1).1, keypair(2).1];
+        let redeem_script = Script::new_multisig(2, &keys);
+        let script_pub_key = Script::new_p2sh(&redeem_script.script_hash().unwrap());
+        psbt(script_pub_key, Some(redeem_script))
+    }
+
+    #[test]
+    fn finalize_rejects_malformed_multisig_signatures() {
+        let mut psbt = multisig_psbt();
+        psbt.inputs[0].signatures.insert(keypair(1).1, vec![1]);
+        psbt.inputs[0].signatures.insert(keypair(2).1, vec![2]);
+        assert!(psbt.finalize().is_err());
+    }
+
+    #[test]
+    fn finalize_rejects_a_wrong_length_p2pkh_key() {
+        let pub_key = vec![7; 5];
+        let mut hash = pub_key.clone();
+        hash_pub_key(&mut hash);
+        let mut psbt = psbt(Script::new_p2pkh(&hash), None);
+        psbt.inputs[0].signatures.insert(pub_key, vec![0; 64]);
+        assert!(psbt.finalize().is_err());
+    }
+
+    #[test]
+    fn finalize_rejects_a_foreign_redeem_script() {
+        let mut psbt = multisig_psbt();
+        for seed in [1, 2] {
+            psbt.sign(&keypair(seed).0).unwrap();
+        }
+        assert!(psbt.finalize().is_ok());
+        psbt.inputs[0].redeem_script = Some(Script::new_multisig(1, &[keypair(3).1]));
+        assert!(psbt.finalize().is_err());
+    }
+
+    #[test]
+    fn combine_merges_cosigner_signatures() {
+        let mut first = multisig_psbt();
+        let mut second = multisig_psbt();
+        first.sign(&keypair(1).0).unwrap();
+        second.sign(&keypair(2).0).unwrap();
+        first.combine(&second).unwrap();
+        assert_eq!(first.inputs[0].signatures.len(), 2);
+        assert!(first.finalize().is_ok());
+    }
+
+    #[test]
+    fn combine_rejects_a_foreign_redeem_script() {
+        let mut psbt = multisig_psbt();
+        psbt.inputs[0].redeem_script = None;
+        let mut other = multisig_psbt();
+        other.inputs[0].redeem_script = Some(Script::new_multisig(1, &[keypair(3).1]));
+        assert!(psbt.combine(&other).is_err());
+        assert!(psbt.inputs[0].redeem_script.is_none());
+    }
+
+    #[test]
+    fn combine_rejects_signatures_of_non_cosigners() {
+        let mut psbt = multisig_psbt();
+        let (secret_key, pub_key) = keypair(3);
+        let sighash = psbt
+            .tx
+            .signature_hash(0, &psbt.inputs[0].prev_output.script_pub_key)
+            .unwrap();
+        let mut other = multisig_psbt();
+        other.inputs[0]
+            .signatures
+            .insert(pub_key, ed25519::signature(&sighash, &secret_key).to_vec());
+        assert!(psbt.combine(&other).is_err());
+    }
+
+    #[test]
+    fn combine_rejects_invalid_cosigner_signatures() {
+        let mut psbt = multisig_psbt();
+        let mut other = multisig_psbt();
+        other.inputs[0].signatures.insert(keypair(1).1, vec![0; 64]);
+        assert!(psbt.combine(&other).is_err());
+        assert!(psbt.inputs[0].signatures.is_empty());
+    }
+}
@@ -0,0 +1,567 @@
+use crate::error::Result;
+use crate::wallet::hash_pub_key;
+use crypto::ed25519;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Signature checks charged for an `OP_CHECKMULTISIG` whose key count is unknown.
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+/// Lock-time arguments below this are block heights, at or above it unix timestamps.
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    Push(Vec<u8>),
+    Dup,
+    Hash160,
+    Equal,
+    EqualVerify,
+    CheckSig,
+    CheckMultiSig,
+    CheckLockTimeVerify,
+    Return,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Script(pub Vec<Op>);
+
+/// What a script may inspect about the transaction spending it.
+pub struct ScriptEnv<'a> {
+    pub sighash: &'a [u8],
+    pub height: usize,
+    pub time: u64,
+}
+
+impl Script {
+    pub fn new_p2pkh(pub_key_hash: &[u8]) -> Script {
+        Script(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    pub fn new_p2pkh_sig(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script(vec![Op::Push(signature.to_vec()), Op::Push(pub_key.to_vec())])
+    }
+
+    /// Pay-to-script-hash: the spender reveals the redeem script hashing to
+    /// `script_hash` as the last push of its unlocking script.
+    pub fn new_p2sh(script_hash: &[u8]) -> Script {
+        Script(vec![Op::Hash160, Op::Push(script_hash.to_vec()), Op::Equal])
+    }
+
+    pub fn new_multisig(required: usize, pub_keys: &[Vec<u8>]) -> Script {
+        let mut ops = vec![push_num(required as u64)];
+        ops.extend(pub_keys.iter().map(|k| Op::Push(k.clone())));
+        ops.push(push_num(pub_keys.len() as u64));
+        ops.push(Op::CheckMultiSig);
+        Script(ops)
+    }
+
+    /// Unlocking script for a P2SH multisig: the signatures over `sighash` in the
+    /// order of the redeem script's keys, followed by the redeem script itself.
+    pub fn new_multisig_sig(
+        sighash: &[u8],
+        signatures: &[Vec<u8>],
+        redeem_script: &Script,
+    ) -> Result<Script> {
+        let (required, pub_keys) = redeem_script.multisig_keys().unwrap_or_default();
+        let mut ops: Vec<Op> = pub_keys
+            .iter()
+            .filter_map(|key| {
+                signatures
+                    .iter()
+                    .find(|sig| verify_signature(sighash, key, sig))
+                    .map(|sig| Op::Push(sig.clone()))
+            })
+            .take(required)
+            .collect();
+        ops.push(Op::Push(bincode::serialize(redeem_script)?));
+        Ok(Script(ops))
+    }
+
+    pub fn new_timelocked(lock_time: u64, pub_key_hash: &[u8]) -> Script {
+        let mut ops = vec![push_num(lock_time), Op::CheckLockTimeVerify];
+        ops.extend(Script::new_p2pkh(pub_key_hash).0);
+        Script(ops)
+    }
+
+    pub fn new_data(data: &[u8]) -> Script {
+        Script(vec![Op::Return, Op::Push(data.to_vec())])
+    }
+
+    /// Returns the key hash if this is a standard pay-to-pub-key-hash script.
+    pub fn p2pkh_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [
+                Op::Dup,
+                Op::Hash160,
+                Op::Push(hash),
+                Op::EqualVerify,
+                Op::CheckSig,
+            ] => Some(hash),
+            _ => None,
+        }
+    }
+
+    pub fn p2sh_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Hash160, Op::Push(hash), Op::Equal] => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// Returns the required signature count and keys of an M-of-N multisig script.
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        let (first, rest) = self.0.split_first()?;
+        let (last, rest) = rest.split_last()?;
+        let (count, keys) = rest.split_last()?;
+        match (first, count, last) {
+            (Op::Push(m), Op::Push(n), Op::CheckMultiSig) => {
+                let keys: Option<Vec<Vec<u8>>> = keys
+                    .iter()
+                    .map(|op| match op {
+                        Op::Push(key) => Some(key.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                let keys = keys?;
+                if read_num(n)? as usize != keys.len() {
+                    return None;
+                }
+                Some((read_num(m)? as usize, keys))
+            }
+            _ => None,
+        }
+    }
+
+    /// Hash160 of the serialized script, as committed to by a P2SH output.
+    pub fn script_hash(&self) -> Result<Vec<u8>> {
+        let mut data = bincode::serialize(self)?;
+        hash_pub_key(&mut data);
+        Ok(data)
+    }
+
+    /// Returns the public key if this is a signature-and-key unlocking script.
+    pub fn p2pkh_pub_key(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Push(sig), Op::Push(pub_key)] if sig.len() == 64 && pub_key.len() == 32 => {
+                Some(pub_key)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the payload if this is a data-carrier script.
+    pub fn data(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Return, Op::Push(data)] => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Signature checks running the script may perform. A multisig costs its
+    /// key count when a push right before it states it.
+    pub fn sigop_count(&self) -> usize {
+        let mut count = 0;
+        let mut prev: Option<&Op> = None;
+        for op in &self.0 {
+            match op {
+                Op::CheckSig => count += 1,
+                Op::CheckMultiSig => {
+                    count += match prev {
+                        Some(Op::Push(n)) => {
+                            read_num(n).map_or(MAX_PUBKEYS_PER_MULTISIG, |n| n as usize)
+                        }
+                        _ => MAX_PUBKEYS_PER_MULTISIG,
+                    }
+                }
+                _ => {}
+            }
+            prev = Some(op);
+        }
+        count
+    }
+
+    /// The redeem script a P2SH unlocking script reveals as its last push.
+    pub fn redeem_script(&self) -> Option<Script> {
+        match self.0.last() {
+            Some(Op::Push(data)) => bincode::deserialize(data).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn is_unspendable(&self) -> bool {
+        matches!(self.0.first(), Some(Op::Return))
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.0.iter().all(|op| matches!(op, Op::Push(_)))
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ops: Vec<String> = self
+            .0
+            .iter()
+            .map(|op| match op {
+                Op::Push(data) => hex::encode(data),
+                Op::Dup => String::from("OP_DUP"),
+                Op::Hash160 => String::from("OP_HASH160"),
+                Op::Equal => String::from("OP_EQUAL"),
+                Op::EqualVerify => String::from("OP_EQUALVERIFY"),
+                Op::CheckSig => String::from("OP_CHECKSIG"),
+                Op::CheckMultiSig => String::from("OP_CHECKMULTISIG"),
+                Op::CheckLockTimeVerify => String::from("OP_CHECKLOCKTIMEVERIFY"),
+                Op::Return => String::from("OP_RETURN"),
+            })
+            .collect();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+/// Runs `script_sig` and then `script_pub_key` on a shared stack and reports
+/// whether the output is unlocked. For P2SH outputs the revealed redeem script
+/// is run as well, on the stack left by `script_sig`.
+pub fn execute(script_sig: &Script, script_pub_key: &Script, env: &ScriptEnv) -> bool {
+    if !script_sig.is_push_only() {
+        return false;
+    }
+    let mut stack: Vec<Vec<u8>> = Vec::new();
+    if !run(script_sig, &mut stack, env) {
+        return false;
+    }
+    let mut redeem_stack = stack.clone();
+    if !run(script_pub_key, &mut stack, env) || !matches!(stack.last(), Some(top) if is_true(top))
+    {
+        return false;
+    }
+    if script_pub_key.p2sh_hash().is_none() {
+        return true;
+    }
+    redeem_stack.pop();
+    let redeem_script = match script_sig.redeem_script() {
+        Some(script) => script,
+        None => return false,
+    };
+    run(&redeem_script, &mut redeem_stack, env)
+        && matches!(redeem_stack.last(), Some(top) if is_true(top))
+}
+
+fn run(script: &Script, stack: &mut Vec<Vec<u8>>, env: &ScriptEnv) -> bool {
+    script.0.iter().all(|op| step(op, stack, env).is_some())
+}
+
+/// Executes one opcode, returning `None` when the script fails.
+fn step(op: &Op, stack: &mut Vec<Vec<u8>>, env: &ScriptEnv) -> Option<()> {
+    match op {
+        Op::Push(data) => stack.push(data.clone()),
+        Op::Dup => {
+            let top = stack.last()?.clone();
+            stack.push(top);
+        }
+        Op::Hash160 => {
+            let mut data = stack.pop()?;
+            hash_pub_key(&mut data);
+            stack.push(data);
+        }
+        Op::Equal => {
+            let a = stack.pop()?;
+            let b = stack.pop()?;
+            stack.push(bool_bytes(a == b));
+        }
+        Op::EqualVerify => {
+            let a = stack.pop()?;
+            let b = stack.pop()?;
+            if a != b {
+                return None;
+            }
+        }
+        Op::CheckSig => {
+            let pub_key = stack.pop()?;
+            let signature = stack.pop()?;
+            stack.push(bool_bytes(check_sig(&signature, &pub_key, env)));
+        }
+        Op::CheckMultiSig => {
+            let n = read_num(&stack.pop()?)? as usize;
+            if stack.len() < n {
+                return None;
+            }
+            let pub_keys = stack.split_off(stack.len() - n);
+            let m = read_num(&stack.pop()?)? as usize;
+            if m > n || stack.len() < m {
+                return None;
+            }
+            let signatures = stack.split_off(stack.len() - m);
+            // signatures must appear in the same order as their keys
+            let mut keys = pub_keys.iter();
+            let ok = signatures
+                .iter()
+                .all(|sig| keys.any(|key| check_sig(sig, key, env)));
+            stack.push(bool_bytes(ok));
+        }
+        Op::CheckLockTimeVerify => {
+            // consumes its argument since there is no OP_DROP
+            let lock_time = read_num(&stack.pop()?)?;
+            let reached = if lock_time < LOCKTIME_THRESHOLD {
+                env.height as u64 >= lock_time
+            } else {
+                env.time >= lock_time
+            };
+            if !reached {
+                return None;
+            }
+        }
+        Op::Return => return None,
+    }
+    Some(())
+}
+
+fn check_sig(signature: &[u8], pub_key: &[u8], env: &ScriptEnv) -> bool {
+    verify_signature(env.sighash, pub_key, signature)
+}
+
+/// Checks an ed25519 signature, rejecting keys and signatures of the wrong
+/// length, on which `ed25519::verify` would panic.
+pub fn verify_signature(message: &[u8], pub_key: &[u8], signature: &[u8]) -> bool {
+    signature.len() == 64 && pub_key.len() == 32 && ed25519::verify(message, pub_key, signature)
+}
+
+fn push_num(n: u64) -> Op {
+    let bytes = n.to_le_bytes();
+    let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    Op::Push(bytes[..len].to_vec())
+}
+
+fn read_num(data: &[u8]) -> Option<u64> {
+    if data.len() > 8 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..data.len()].copy_from_slice(data);
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn bool_bytes(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { Vec::new() }
+}
+
+fn is_true(data: &[u8]) -> bool {
+    data.iter().any(|b| *b != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGHASH: &[u8] = b"sighash of the spending transaction";
+
+    fn env(height: usize, time: u64) -> ScriptEnv<'static> {
+        ScriptEnv {
+            sighash: SIGHASH,
+            height,
+            time,
+        }
+    }
+
+    /// A public key and its signature over `SIGHASH`.
+    fn key(seed: u8) -> (Vec<u8>, Vec<u8>) {
+        let (secret_key, public_key) = ed25519::keypair(&[seed; 32]);
+        let signature = ed25519::signature(SIGHASH, &secret_key);
+        (public_key.to_vec(), signature.to_vec())
+    }
+
+    fn key_hash(public_key: &[u8]) -> Vec<u8> {
+        let mut hash = public_key.to_vec();
+        hash_pub_key(&mut hash);
+        hash
+    }
+
+    #[test]
+    fn p2pkh_accepts_the_key_owner() {
+        let (public_key, signature) = key(1);
+        let script_pub_key = Script::new_p2pkh(&key_hash(&public_key));
+        let script_sig = Script::new_p2pkh_sig(&signature, &public_key);
+        assert!(execute(&script_sig, &script_pub_key, &env(1, 0)));
+    }
+
+    #[test]
+    fn p2pkh_rejects_another_key_or_a_bad_signature() {
+        let (public_key, signature) = key(1);
+        let (other_key, other_signature) = key(2);
+        let script_pub_key = Script::new_p2pkh(&key_hash(&public_key));
+        let other = Script::new_p2pkh_sig(&other_signature, &other_key);
+        assert!(!execute(&other, &script_pub_key, &env(1, 0)));
+        let mut forged = signature.clone();
+        forged[0] ^= 1;
+        let forged = Script::new_p2pkh_sig(&forged, &public_key);
+        assert!(!execute(&forged, &script_pub_key, &env(1, 0)));
+    }
+
+    fn bare_multisig() -> (Script, Vec<Vec<u8>>) {
+        let keys: Vec<(Vec<u8>, Vec<u8>)> = (1..=3).map(key).collect();
+        let pub_keys: Vec<Vec<u8>> = keys.iter().map(|(k, _)| k.clone()).collect();
+        let signatures = keys.into_iter().map(|(_, sig)| sig).collect();
+        (Script::new_multisig(2, &pub_keys), signatures)
+    }
+
+    fn pushes(signatures: &[&Vec<u8>]) -> Script {
+        Script(
+            signatures
+                .iter()
+                .map(|sig| Op::Push(sig.to_vec()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn multisig_accepts_signatures_in_key_order() {
+        let (script_pub_key, sigs) = bare_multisig();
+        for pair in [
+            [&sigs[0], &sigs[1]],
+            [&sigs[0], &sigs[2]],
+            [&sigs[1], &sigs[2]],
+        ] {
+            assert!(execute(&pushes(&pair), &script_pub_key, &env(1, 0)));
+        }
+    }
+
+    #[test]
+    fn multisig_rejects_signatures_out_of_key_order() {
+        let (script_pub_key, sigs) = bare_multisig();
+        assert!(!execute(
+            &pushes(&[&sigs[1], &sigs[0]]),
+            &script_pub_key,
+            &env(1, 0)
+        ));
+    }
+
+    #[test]
+    fn multisig_rejects_fewer_than_required_signatures() {
+        let (script_pub_key, sigs) = bare_multisig();
+        assert!(!execute(&pushes(&[&sigs[0]]), &script_pub_key, &env(1, 0)));
+        assert!(!execute(
+            &pushes(&[&sigs[0], &sigs[0]]),
+            &script_pub_key,
+            &env(1, 0)
+        ));
+    }
+
+    fn multisig() -> (Script, Script, Vec<Vec<u8>>) {
+        let keys: Vec<(Vec<u8>, Vec<u8>)> = (1..=3).map(key).collect();
+        let pub_keys: Vec<Vec<u8>> = keys.iter().map(|(k, _)| k.clone()).collect();
+        let redeem_script = Script::new_multisig(2, &pub_keys);
+        let script_pub_key = Script::new_p2sh(&redeem_script.script_hash().unwrap());
+        let signatures = keys.into_iter().map(|(_, sig)| sig).collect();
+        (redeem_script, script_pub_key, signatures)
+    }
+
+    fn multisig_sig(signatures: &[&Vec<u8>], redeem_script: &Script) -> Script {
+        let mut ops: Vec<Op> = signatures
+            .iter()
+            .map(|sig| Op::Push(sig.to_vec()))
+            .collect();
+        ops.push(Op::Push(bincode::serialize(redeem_script).unwrap()));
+        Script(ops)
+    }
+
+    #[test]
+    fn p2sh_multisig_accepts_signatures_in_key_order() {
+        let (redeem_script, script_pub_key, sigs) = multisig();
+        for pair in [
+            [&sigs[0], &sigs[1]],
+            [&sigs[0], &sigs[2]],
+            [&sigs[1], &sigs[2]],
+        ] {
+            let script_sig = multisig_sig(&pair, &redeem_script);
+            assert!(execute(&script_sig, &script_pub_key, &env(1, 0)));
+        }
+        let built = Script::new_multisig_sig(SIGHASH, &sigs, &redeem_script).unwrap();
+        assert!(execute(&built, &script_pub_key, &env(1, 0)));
+    }
+
+    #[test]
+    fn new_multisig_sig_skips_malformed_signatures() {
+        let (redeem_script, script_pub_key, sigs) = multisig();
+        let malformed = vec![vec![1], vec![0; 63], sigs[0].clone(), sigs[2].clone()];
+        let script_sig = Script::new_multisig_sig(SIGHASH, &malformed, &redeem_script).unwrap();
+        assert_eq!(script_sig.0.len(), 3);
+        assert!(execute(&script_sig, &script_pub_key, &env(1, 0)));
+    }
+
+    #[test]
+    fn p2sh_multisig_rejects_signatures_out_of_key_order() {
+        let (redeem_script, script_pub_key, sigs) = multisig();
+        let script_sig = multisig_sig(&[&sigs[1], &sigs[0]], &redeem_script);
+        assert!(!execute(&script_sig, &script_pub_key, &env(1, 0)));
+    }
+
+    #[test]
+    fn p2sh_multisig_rejects_fewer_than_required_signatures() {
+        let (redeem_script, script_pub_key, sigs) = multisig();
+        let script_sig = multisig_sig(&[&sigs[0]], &redeem_script);
+        assert!(!execute(&script_sig, &script_pub_key, &env(1, 0)));
+        let repeated = multisig_sig(&[&sigs[0], &sigs[0]], &redeem_script);
+        assert!(!execute(&repeated, &script_pub_key, &env(1, 0)));
+    }
+
+    #[test]
+    fn p2sh_rejects_another_redeem_script() {
+        let (_, script_pub_key, sigs) = multisig();
+        let (other_key, _) = key(9);
+        let other = Script::new_multisig(1, &[other_key]);
+        let script_sig = multisig_sig(&[&sigs[0]], &other);
+        assert!(!execute(&script_sig, &script_pub_key, &env(1, 0)));
+    }
+
+    #[test]
+    fn cltv_height_lock() {
+        let (public_key, signature) = key(1);
+        let script_pub_key = Script::new_timelocked(100, &key_hash(&public_key));
+        let script_sig = Script::new_p2pkh_sig(&signature, &public_key);
+        assert!(!execute(&script_sig, &script_pub_key, &env(99, u64::MAX)));
+        assert!(execute(&script_sig, &script_pub_key, &env(100, 0)));
+    }
+
+    #[test]
+    fn cltv_time_lock() {
+        let lock_time = LOCKTIME_THRESHOLD + 1_000;
+        let (public_key, signature) = key(1);
+        let script_pub_key = Script::new_timelocked(lock_time, &key_hash(&public_key));
+        let script_sig = Script::new_p2pkh_sig(&signature, &public_key);
+        assert!(!execute(
+            &script_sig,
+            &script_pub_key,
+            &env(usize::MAX, lock_time - 1)
+        ));
+        assert!(execute(&script_sig, &script_pub_key, &env(0, lock_time)));
+    }
+
+    #[test]
+    fn op_return_is_unspendable() {
+        let (public_key, signature) = key(1);
+        let script_pub_key = Script::new_data(b"anchored");
+        assert!(script_pub_key.is_unspendable());
+        for script_sig in [
+            Script::default(),
+            Script(vec![Op::Push(vec![1])]),
+            Script::new_p2pkh_sig(&signature, &public_key),
+        ] {
+            assert!(!execute(&script_sig, &script_pub_key, &env(1, 0)));
+        }
+    }
+
+    #[test]
+    fn script_sig_must_be_push_only() {
+        // a trivially satisfiable lock, so only the opcode in script_sig fails
+        let script_pub_key = Script(vec![Op::EqualVerify, Op::Push(vec![1])]);
+        let pushes = Script(vec![Op::Push(vec![7]), Op::Push(vec![7])]);
+        assert!(execute(&pushes, &script_pub_key, &env(1, 0)));
+        let with_opcode = Script(vec![Op::Push(vec![7]), Op::Dup]);
+        assert!(!execute(&with_opcode, &script_pub_key, &env(1, 0)));
+    }
+}
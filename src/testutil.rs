@@ -0,0 +1,222 @@
+//! A throwaway chain for tests, with confirmed outputs of one key to spend.
+
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::script::{Op, Script};
+use crate::transaction::Transaction;
+use crate::tx::{SEQUENCE_FINAL, TxInput, TxOutput};
+use crate::wallet::{hash_pub_key, pub_key_hash_to_address};
+use crypto::ed25519;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// An address outside the test key, for rewards.
+pub const ADDRESS: &str = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2";
+/// Value of each output of `TestChain::funding`.
+pub const COIN: u64 = 100_000_000;
+/// Outputs of `TestChain::funding`.
+pub const FUNDING_OUTPUTS: usize = 10;
+
+/// Mining is slow, so every test shares one chain, left in the temp
+/// directory, and gets a pool of its own.
+static CHAIN: OnceLock<(Blockchain, Transaction)> = OnceLock::new();
+
+pub struct TestChain {
+    pub bc: Blockchain,
+    pub pool: Mempool,
+    /// The confirmed transaction splitting the genesis reward into
+    /// `FUNDING_OUTPUTS` outputs of one `COIN`, all to the test key.
+    pub funding: Transaction,
+    dir: PathBuf,
+}
+
+impl TestChain {
+    /// Opens an empty pool under a fresh directory named after `name`,
+    /// removed again on drop, on top of the shared chain.
+    pub fn new(name: &str) -> TestChain {
+        let (bc, funding) = CHAIN.get_or_init(mine_chain);
+        let dir = temp_dir(name);
+        let pool = Mempool::open(dir.to_str().unwrap()).unwrap();
+        TestChain {
+            bc: bc.clone(),
+            pool,
+            funding: funding.clone(),
+            dir,
+        }
+    }
+
+    /// A transaction `id` spending `inputs`, as `(txid, vout)`, with
+    /// `sequence` into outputs of `values` to the test key, signed.
+    pub fn spend(
+        &self,
+        id: &str,
+        inputs: &[(&str, i32)],
+        values: &[u64],
+        sequence: u32,
+    ) -> Transaction {
+        spend(&self.bc, self.pool.get_all(), id, inputs, values, sequence)
+    }
+
+    /// Like `spend`, into `vout`.
+    pub fn spend_to(
+        &self,
+        id: &str,
+        inputs: &[(&str, i32)],
+        vout: Vec<TxOutput>,
+        sequence: u32,
+    ) -> Transaction {
+        spend_to(&self.bc, self.pool.get_all(), id, inputs, vout, sequence)
+    }
+
+    /// A transaction locking output `vout` of `funding` to a redeem script
+    /// costing `multisigs` times `MAX_PUBKEYS_PER_MULTISIG` signature checks,
+    /// and the transaction `id` spending it.
+    pub fn costly_spend(
+        &self,
+        id: &str,
+        vout: i32,
+        multisigs: usize,
+    ) -> (Transaction, Transaction) {
+        let (redeem_script, script_sig) = costly_redeem_script(multisigs);
+        let output = TxOutput {
+            value: Amount::from_base(COIN - 1_000),
+            script_pub_key: Script::new_p2sh(&redeem_script.script_hash().unwrap()),
+        };
+        let lock = self.spend_to(
+            &format!("{}-lock", id),
+            &[self.coin(vout)],
+            vec![output],
+            SEQUENCE_FINAL,
+        );
+        let mut input = TxInput::new(lock.id.clone(), 0, SEQUENCE_FINAL);
+        input.script_sig = script_sig;
+        let spend = Transaction {
+            id: id.to_string(),
+            vin: vec![input],
+            vout: vec![TxOutput {
+                value: Amount::from_base(COIN - 2_000),
+                script_pub_key: lock.vout[0].script_pub_key.clone(),
+            }],
+            lock_time: 0,
+        };
+        (lock, spend)
+    }
+
+    /// Signs `tx` again after a change.
+    pub fn sign(&self, tx: &mut Transaction) {
+        sign(&self.bc, self.pool.get_all(), tx);
+    }
+
+    /// Output `vout` of `funding`, as an input for `spend`.
+    pub fn coin(&self, vout: i32) -> (&str, i32) {
+        (&self.funding.id, vout)
+    }
+}
+
+impl Drop for TestChain {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Mines a genesis block paying the test key and a block with the funding
+/// transaction.
+fn mine_chain() -> (Blockchain, Transaction) {
+    let (_, public_key) = keypair();
+    let mut pub_key_hash = public_key.to_vec();
+    hash_pub_key(&mut pub_key_hash);
+    let address = pub_key_hash_to_address(&pub_key_hash).unwrap();
+    let dir = temp_dir("chain");
+    let mut bc = Blockchain::create_blockchain_at(dir.to_str().unwrap(), address).unwrap();
+
+    let genesis = bc.iter().next().unwrap();
+    let reward = genesis.get_transaction()[0].id.clone();
+    let funding = spend(
+        &bc,
+        &HashMap::new(),
+        "funding",
+        &[(&reward, 0)],
+        &[COIN; FUNDING_OUTPUTS],
+        SEQUENCE_FINAL,
+    );
+    bc.add_block(vec![funding.clone()]).unwrap();
+    (bc, funding)
+}
+
+/// A redeem script anyone can satisfy that costs `multisigs` times
+/// `MAX_PUBKEYS_PER_MULTISIG` signature checks, and the unlocking script
+/// revealing it.
+fn costly_redeem_script(multisigs: usize) -> (Script, Script) {
+    let mut redeem_script = Vec::new();
+    let mut script_sig = Vec::new();
+    for _ in 0..multisigs {
+        // a 0-of-0 multisig, not preceded by a push, so it counts in full
+        redeem_script.extend([Op::CheckMultiSig, Op::Push(vec![1]), Op::EqualVerify]);
+        script_sig.extend([Op::Push(Vec::new()), Op::Push(Vec::new())]);
+    }
+    redeem_script.push(Op::Push(vec![1]));
+    let redeem_script = Script(redeem_script);
+    script_sig.push(Op::Push(bincode::serialize(&redeem_script).unwrap()));
+    (redeem_script, Script(script_sig))
+}
+
+fn spend(
+    bc: &Blockchain,
+    pending: &HashMap<String, Transaction>,
+    id: &str,
+    inputs: &[(&str, i32)],
+    values: &[u64],
+    sequence: u32,
+) -> Transaction {
+    let (_, public_key) = keypair();
+    let mut pub_key_hash = public_key.to_vec();
+    hash_pub_key(&mut pub_key_hash);
+    let vout = values
+        .iter()
+        .map(|value| TxOutput {
+            value: Amount::from_base(*value),
+            script_pub_key: Script::new_p2pkh(&pub_key_hash),
+        })
+        .collect();
+    spend_to(bc, pending, id, inputs, vout, sequence)
+}
+
+fn spend_to(
+    bc: &Blockchain,
+    pending: &HashMap<String, Transaction>,
+    id: &str,
+    inputs: &[(&str, i32)],
+    vout: Vec<TxOutput>,
+    sequence: u32,
+) -> Transaction {
+    let mut tx = Transaction {
+        id: id.to_string(),
+        vin: inputs
+            .iter()
+            .map(|(txid, vout)| TxInput::new(txid.to_string(), *vout, sequence))
+            .collect(),
+        vout,
+        lock_time: 0,
+    };
+    sign(bc, pending, &mut tx);
+    tx
+}
+
+fn sign(bc: &Blockchain, pending: &HashMap<String, Transaction>, tx: &mut Transaction) {
+    let (secret_key, _) = keypair();
+    let prev_txs = bc.get_prev_txs_with(tx, pending).unwrap();
+    tx.sign(&secret_key, &prev_txs).unwrap();
+}
+
+fn keypair() -> ([u8; 64], [u8; 32]) {
+    ed25519::keypair(&[7; 32])
+}
+
+/// A fresh directory for `name`, unique to this test run.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("my-chain-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
@@ -1,198 +1,807 @@
-use std::vec;
-
+use crate::amount::Amount;
 use crate::blockchain::Blockchain;
-use crate::error::Result;
-use crate::tx::{TxInput, TxOutput};
-use crate::wallet::{hash_pub_key, Wallets};
+use crate::coinselect::{CoinSelection, Utxo};
+use crate::error::{ChainError, Result};
+use crate::script::{self, LOCKTIME_THRESHOLD, Op, Script, ScriptEnv};
+use crate::mempool::Mempool;
+use crate::policy::DUST_THRESHOLD;
+use crate::tx::{MAX_BIP125_RBF_SEQUENCE, RelativeLock, SEQUENCE_FINAL, TxInput, TxOutput};
+use crate::wallet::{address_to_script, hash_pub_key, script_to_address, Wallets};
 use crypto::digest::Digest;
 use crypto::ed25519;
 use crypto::sha2::Sha256;
-use failure::format_err;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+/// Block reward in base units: 100 coins.
+pub const SUBSIDY: Amount = Amount::from_base(100 * 100_000_000);
+
+/// Optional settings for building a payment.
+#[derive(Debug, Clone, Default)]
+pub struct TxOptions {
+    pub fee: Amount,
+    pub lock_time: u64,
+    /// Opt in to being replaced by a transaction paying a higher fee.
+    pub replaceable: bool,
+    /// How long after the outputs it spends confirmed the transaction may be
+    /// mined. A relative lock also opts in to replacement.
+    pub relative_lock: Option<RelativeLock>,
+    pub coin_selection: CoinSelection,
+    /// Outputs to spend, as `(txid, vout)`, instead of letting
+    /// `coin_selection` choose.
+    pub utxos: Vec<(String, i32)>,
+    /// Send change back to the address of the first input instead of to a
+    /// fresh change address of the wallet.
+    pub reuse_change: bool,
+}
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TxInput>,
     pub vout: Vec<TxOutput>,
+    /// Earliest block height, or unix time from `LOCKTIME_THRESHOLD` on, the
+    /// transaction may be mined at. Zero means no lock.
+    pub lock_time: u64,
 }
 
 impl Transaction {
-    pub fn new_UTXO(from: &str, to: &str, amount: i32, bc: &Blockchain) -> Result<Transaction> {
-        let mut vin = Vec::new();
-        let wallets= Wallets::new()?;
-        let wallet = match wallets.get_wallet(from){
-            Some(w) => w,
-            None => return Err(format_err!("Wallet not found")),
-        };
-        if let None = wallets.get_wallet(&to){
-            return Err(format_err!("to Wallet not found"));
+    /// Builds a payment from `from`, which may be a key or multisig address of
+    /// `wallets`. Multisig spends come back with only the local cosigners' signatures.
+    pub fn new_utxo(
+        wallets: &mut Wallets,
+        from: &str,
+        to: &str,
+        amount: Amount,
+        options: &TxOptions,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        Transaction::new_signed(wallets, from, vec![TxOutput::new(amount, to)?], options, bc)
+    }
+
+    /// Builds a transaction from `from` anchoring `data` in an unspendable
+    /// output. How much data the mempool relays is up to its policy.
+    pub fn new_data(
+        wallets: &mut Wallets,
+        from: &str,
+        data: &[u8],
+        options: &TxOptions,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        Transaction::new_signed(wallets, from, vec![TxOutput::new_data(data)], options, bc)
+    }
+
+    fn new_signed(
+        wallets: &mut Wallets,
+        from: &str,
+        outputs: Vec<TxOutput>,
+        options: &TxOptions,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        wallets.check_can_sign(from)?;
+        if wallets.get_wallet(from).is_none() && wallets.get_multisig(from).is_none() {
+            return Err(ChainError::WalletNotFound(from.to_string()));
+        }
+        let change = Transaction::change_address(wallets, options)?;
+        let mut tx = Transaction::new_unsigned(from, change.as_deref(), outputs, options, bc)?;
+        let mempool = Mempool::new()?;
+        tx.sign_with_wallets(wallets, &bc.get_prev_txs_with(&tx, mempool.get_all())?)?;
+        if tx.pays_to(change.as_deref())? {
+            // keep the change address from being handed out again
+            wallets.save_all()?;
         }
+        Ok(tx)
+    }
 
-        let mut pub_key_hash = wallet.public_key.clone();
-        hash_pub_key(&mut pub_key_hash);
-        let acc_v = bc.find_spendable_outputs(&from, amount);
-        if acc_v.0 < amount {
-            return Err(format_err!(
-                "Not enough balance: current balance {}",
-                acc_v.0
-            ));
-        }
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TxInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
+    /// Builds a payment funded by any of the keys of `wallets`, watch-only and
+    /// multisig addresses aside, from the outputs the wallet has recorded
+    /// plus its unconfirmed ones.
+    pub fn new_wallet_payment(
+        wallets: &mut Wallets,
+        to: &str,
+        amount: Amount,
+        options: &TxOptions,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        wallets.check_unlocked()?;
+        wallets.sync(bc)?;
+        let mempool = Mempool::new()?;
+        let spent = mempool.spent_outputs();
+        let mut confirmed = Vec::new();
+        for out in wallets.get_utxos() {
+            if wallets.get_wallet(&out.address).is_some()
+                && !spent.contains(&(out.txid.clone(), out.vout))
+            {
+                confirmed.push(Utxo {
+                    txid: out.txid.clone(),
+                    vout: out.vout,
+                    output: TxOutput::new(out.value, &out.address)?,
+                });
             }
         }
-        let mut vout = vec![
-            TxOutput::new(amount, to.to_string())?,
-        ];
-        if acc_v.0 > amount {
-            vout.push(TxOutput::new(acc_v.0 - amount, from.to_string())?);
+        let mut candidates = confirmed.clone();
+        candidates.extend(mempool.unspent_outputs_matching(|script| {
+            matches!(script_to_address(script), Ok(Some(address)) if wallets.get_wallet(&address).is_some())
+        }));
+        let change = Transaction::change_address(wallets, options)?;
+        let mut tx = Transaction::fund(
+            "the wallet",
+            confirmed,
+            candidates,
+            change.as_deref(),
+            vec![TxOutput::new(amount, to)?],
+            options,
+        )?;
+        tx.sign_with_wallets(wallets, &bc.get_prev_txs_with(&tx, mempool.get_all())?)?;
+        wallets.save_all()?;
+        Ok(tx)
+    }
+
+    /// A fresh change address, or `None` to reuse the input's address.
+    fn change_address(wallets: &mut Wallets, options: &TxOptions) -> Result<Option<String>> {
+        if options.reuse_change {
+            return Ok(None);
+        }
+        Ok(Some(wallets.get_change_address()?))
+    }
+
+    fn pays_to(&self, address: Option<&str>) -> Result<bool> {
+        let Some(address) = address else {
+            return Ok(false);
+        };
+        let script_pub_key = address_to_script(address)?;
+        Ok(self.vout.iter().any(|out| out.script_pub_key == script_pub_key))
+    }
+
+    /// Builds an unsigned payment from any address, for signing elsewhere.
+    pub fn new_unsigned_utxo(
+        from: &str,
+        to: &str,
+        amount: Amount,
+        options: &TxOptions,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        Transaction::new_unsigned(from, None, vec![TxOutput::new(amount, to)?], options, bc)
+    }
+
+    /// Funds `outputs` from `from`, sending any change to `change`, or back
+    /// to `from` if `None`.
+    fn new_unsigned(
+        from: &str,
+        change: Option<&str>,
+        vout: Vec<TxOutput>,
+        options: &TxOptions,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        let mempool = Mempool::new()?;
+        let script_pub_key = address_to_script(from)?;
+        let confirmed = bc.find_spendable_outputs(&script_pub_key, &mempool.spent_outputs());
+        let mut candidates = confirmed.clone();
+        candidates.extend(mempool.unspent_outputs(&script_pub_key));
+        Transaction::fund(from, confirmed, candidates, change, vout, options)
+    }
+
+    /// Selects among `candidates`, preferring the `confirmed` ones, enough to
+    /// pay `vout` and the fee. Change goes to `change`, or back to the
+    /// address of the first selected input if `None`. `from` names the
+    /// funding source in errors.
+    fn fund(
+        from: &str,
+        confirmed: Vec<Utxo>,
+        candidates: Vec<Utxo>,
+        change: Option<&str>,
+        mut vout: Vec<TxOutput>,
+        options: &TxOptions,
+    ) -> Result<Transaction> {
+        // a lock time only applies when some input is not final
+        let sequence = if let Some(lock) = options.relative_lock {
+            lock.to_sequence()?
+        } else if options.replaceable {
+            MAX_BIP125_RBF_SEQUENCE
+        } else if options.lock_time != 0 {
+            SEQUENCE_FINAL - 1
+        } else {
+            SEQUENCE_FINAL
+        };
+        let needed = Amount::sum(vout.iter().map(|out| out.value).chain([options.fee]))?;
+
+        let selected = if options.utxos.is_empty() {
+            let selector = options.coin_selection.selector();
+            // every transaction needs an input, even a free one carrying data
+            let target = needed.max(Amount::from_base(1));
+            // fall back on unconfirmed change, which also lets a child pay for its parent
+            selector
+                .select(&confirmed, target)
+                .or_else(|| selector.select(&candidates, target))
+        } else {
+            let mut selected = Vec::new();
+            for (txid, vout) in &options.utxos {
+                if selected
+                    .iter()
+                    .any(|utxo: &Utxo| utxo.txid == *txid && utxo.vout == *vout)
+                {
+                    return Err(ChainError::InvalidTx(format!(
+                        "{}:{} is selected more than once",
+                        txid, vout
+                    )));
+                }
+                let utxo = candidates
+                    .iter()
+                    .find(|utxo| utxo.txid == *txid && utxo.vout == *vout)
+                    .ok_or_else(|| {
+                        ChainError::InvalidTx(format!(
+                            "{}:{} is not a spendable output of {}",
+                            txid, vout, from
+                        ))
+                    })?;
+                selected.push(utxo.clone());
+            }
+            Some(selected)
+        };
+        let selected = selected.unwrap_or_default();
+        let total = Amount::sum(selected.iter().map(|utxo| utxo.output.value))?;
+        if total < needed {
+            let have = if options.utxos.is_empty() {
+                Amount::sum(candidates.iter().map(|utxo| utxo.output.value))?
+            } else {
+                total
+            };
+            return Err(ChainError::InsufficientFunds { have, need: needed });
         }
+        // change too small to be worth spending goes to the miner instead
+        if let Some(value) = total.checked_sub(needed).filter(|c| *c >= DUST_THRESHOLD) {
+            vout.push(match change {
+                Some(address) => TxOutput::new(value, address)?,
+                None => TxOutput {
+                    value,
+                    script_pub_key: selected[0].output.script_pub_key.clone(),
+                },
+            });
+        }
+        let vin = selected
+            .into_iter()
+            .map(|utxo| TxInput::new(utxo.txid, utxo.vout, sequence))
+            .collect();
         let mut tx = Transaction {
             id: String::new(),
             vin,
             vout,
+            lock_time: options.lock_time,
         };
         tx.set_id()?;
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-        if data == String::from("") {
+    /// Builds the reward transaction paying the subsidy plus `fees` to `to`.
+    pub fn new_coinbase(to: String, mut data: String, fees: Amount) -> Result<Transaction> {
+        if data.is_empty() {
             data += &format!("Reward to '{}'", to);
         }
 
         let mut tx = Transaction {
             id: String::new(),
             vin: vec![TxInput {
-                txid: String::from(""),
+                txid: String::new(),
                 vout: -1,
-                script_sig: data,
-            }],
-            vout: vec![TxOutput {
-                value: 100,
-                script_pub_key: to,
+                script_sig: Script(vec![Op::Push(data.into_bytes())]),
+                sequence: SEQUENCE_FINAL,
             }],
+            vout: vec![TxOutput::new(Amount::sum([SUBSIDY, fees])?, &to)?],
+            lock_time: 0,
         };
-        tx.set_id();
+        tx.set_id()?;
         Ok(tx)
     }
 
     fn set_id(&mut self) -> Result<()> {
-        let mut hasher = Sha256::new();
-        let data = bincode::serialize(self)?;
-        hasher.input(&data);
-        self.id = hasher.result_str();
+        self.id = self.hash()?;
         Ok(())
     }
 
     pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.vin[0].txid == String::from("") && self.vin[0].vout == -1
+        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
-    pub fn verify(&mut self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
-        if self.is_coinbase() {
-            return Ok(true);
+    /// Whether any input opts in to replace-by-fee.
+    pub fn signals_rbf(&self) -> bool {
+        self.vin.iter().any(|vin| vin.sequence <= MAX_BIP125_RBF_SEQUENCE)
+    }
+
+    /// Inputs minus outputs, erroring if either sum overflows or the outputs
+    /// create value.
+    pub fn fee(&self, prev_txs: &HashMap<String, Transaction>) -> Result<Amount> {
+        let mut input_values = Vec::new();
+        for vin in &self.vin {
+            let prev_out = prev_output(prev_txs, vin)?.ok_or_else(|| {
+                ChainError::InvalidTx(format!("output {}:{} does not exist", vin.txid, vin.vout))
+            })?;
+            input_values.push(prev_out.value);
         }
+        let input_value = Amount::sum(input_values)?;
+        let output_value = Amount::sum(self.vout.iter().map(|out| out.value))?;
+        input_value.checked_sub(output_value).ok_or_else(|| {
+            ChainError::InvalidTx(format!(
+                "{} spends {} but only has {}",
+                self.id, output_value, input_value
+            ))
+        })
+    }
 
+    /// Rebuilds the transaction paying `fee` in total by shrinking its change
+    /// output, and re-signs it with `wallets`. The change output is the last
+    /// one paying a change address of `wallets`, or with reused change the
+    /// last one paying back a spent script.
+    pub fn new_replacement(
+        &self,
+        fee: Amount,
+        prev_txs: &HashMap<String, Transaction>,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        let old_fee = self.fee(prev_txs)?;
+        let extra = match fee.checked_sub(old_fee) {
+            Some(extra) if extra > Amount::ZERO => extra,
+            _ => {
+                return Err(ChainError::InvalidTx(format!(
+                    "new fee must exceed the current fee {}",
+                    old_fee
+                )));
+            }
+        };
+        let mut spent_scripts = Vec::new();
         for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("Previous transaction is not correct"));
+            if let Some(out) = prev_output(prev_txs, vin)? {
+                spent_scripts.push(out.script_pub_key.clone());
             }
         }
+        let pays_change = |out: &TxOutput| {
+            script_to_address(&out.script_pub_key)
+                .ok()
+                .flatten()
+                .is_some_and(|address| wallets.is_change(&address))
+        };
+        let change = self
+            .vout
+            .iter()
+            .rposition(pays_change)
+            .or_else(|| {
+                self.vout
+                    .iter()
+                    .rposition(|out| spent_scripts.contains(&out.script_pub_key))
+            })
+            .ok_or_else(|| ChainError::InvalidTx(String::from("no change output to pay the fee from")))?;
 
-        let mut tx_copy = self.trim_copy();
-
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = self.vin[in_id].signature.clone();
-            if !ed25519::verify(
-                signature.as_slice(),
-                tx_copy.as_bytes(),
-                &tx_copy.vin[in_id].pub_key,
-            ) {
+        let mut tx = self.clone();
+        tx.vout[change].value = tx.vout[change].value.checked_sub(extra).ok_or(
+            ChainError::InsufficientFunds {
+                have: tx.vout[change].value,
+                need: extra,
+            },
+        )?;
+        if tx.vout[change].value < DUST_THRESHOLD {
+            tx.vout.remove(change);
+        }
+        for vin in &mut tx.vin {
+            vin.script_sig = Script::default();
+        }
+        tx.set_id()?;
+        tx.sign_with_wallets(wallets, prev_txs)?;
+        Ok(tx)
+    }
+
+    /// Whether the absolute lock time allows mining at `height` and `time`.
+    pub fn is_final(&self, height: usize, time: u64) -> bool {
+        if self.lock_time == 0 || self.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL) {
+            return true;
+        }
+        if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time <= height as u64
+        } else {
+            self.lock_time <= time
+        }
+    }
+
+    /// Runs every input's unlocking script against the output it spends, as if
+    /// the transaction were included at `height` and `time` (unix seconds).
+    /// Errors if the amounts overflow or the outputs exceed the inputs.
+    pub fn verify(
+        &self,
+        prev_txs: &HashMap<String, Transaction>,
+        height: usize,
+        time: u64,
+    ) -> Result<bool> {
+        Amount::sum(self.vout.iter().map(|out| out.value))?;
+        if self.is_coinbase() {
+            return Ok(true);
+        }
+        if self.vin.is_empty() {
+            return Err(ChainError::InvalidTx(format!("{} has no inputs", self.id)));
+        }
+        self.check_duplicate_inputs()?;
+        self.check_data_outputs()?;
+
+        for in_id in 0..self.vin.len() {
+            let prev_out = match prev_output(prev_txs, &self.vin[in_id])? {
+                Some(out) => out,
+                None => return Ok(false),
+            };
+            let sighash = self.signature_hash(in_id, &prev_out.script_pub_key)?;
+            let env = ScriptEnv {
+                sighash: &sighash,
+                height,
+                time,
+            };
+            if !script::execute(&self.vin[in_id].script_sig, &prev_out.script_pub_key, &env) {
                 return Ok(false);
             }
         }
+        self.fee(prev_txs)?;
 
         Ok(true)
     }
 
+    /// Signature checks spending the inputs runs, counting the revealed
+    /// redeem script of P2SH inputs.
+    pub fn sigop_count(&self, prev_txs: &HashMap<String, Transaction>) -> Result<usize> {
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for vin in &self.vin {
+            if let Some(prev_out) = prev_output(prev_txs, vin)? {
+                count += prev_out.script_pub_key.sigop_count();
+                if prev_out.script_pub_key.p2sh_hash().is_some()
+                    && let Some(redeem_script) = vin.script_sig.redeem_script()
+                {
+                    count += redeem_script.sigop_count();
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Rejects a transaction spending the same output twice, which would
+    /// count its value twice towards the fee.
+    pub fn check_duplicate_inputs(&self) -> Result<()> {
+        let mut seen = HashSet::new();
+        for vin in &self.vin {
+            if !seen.insert((&vin.txid, vin.vout)) {
+                return Err(ChainError::InvalidTx(format!(
+                    "{} spends {}:{} more than once",
+                    self.id, vin.txid, vin.vout
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Requires data-carrier outputs to be well formed and without value,
+    /// since nothing could ever spend them. Their number and size are left
+    /// to the relay policy.
+    fn check_data_outputs(&self) -> Result<()> {
+        for out in &self.vout {
+            if !out.script_pub_key.is_unspendable() {
+                continue;
+            }
+            let reason = match out.script_pub_key.data() {
+                None => Some("a malformed data output"),
+                Some(_) if out.value != Amount::ZERO => Some("a data output carrying value"),
+                Some(_) => None,
+            };
+            if let Some(reason) = reason {
+                return Err(ChainError::InvalidTx(format!("{} has {}", self.id, reason)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Signs every input spending a pay-to-pub-key-hash output of `private_key`.
     pub fn sign(
         &mut self,
         private_key: &[u8],
-        prev_txs: HashMap<String, Transaction>,
+        prev_txs: &HashMap<String, Transaction>,
     ) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
-        for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("Previous transaction is not correct"));
+        // ed25519 secret keys carry the public key in their last 32 bytes
+        let pub_key = &private_key[32..];
+        let mut pub_key_hash = pub_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+
+        for in_id in 0..self.vin.len() {
+            let prev_out = prev_output(prev_txs, &self.vin[in_id])?.ok_or_else(|| {
+                ChainError::InvalidTx(format!(
+                    "output {}:{} does not exist",
+                    self.vin[in_id].txid, self.vin[in_id].vout
+                ))
+            })?;
+            if !prev_out.is_locked_with_key(&pub_key_hash) {
+                continue;
             }
+            let sighash = self.signature_hash(in_id, &prev_out.script_pub_key)?;
+            let signature = ed25519::signature(&sighash, private_key);
+            self.vin[in_id].script_sig = Script::new_p2pkh_sig(&signature, pub_key);
         }
 
-        let mut tx_copy = self.trim_copy();
+        Ok(())
+    }
 
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
+    /// Adds the signature of `private_key` to every input spending the P2SH
+    /// output of `redeem_script`, keeping cosigners' signatures in key order.
+    pub fn sign_multisig(
+        &mut self,
+        private_key: &[u8],
+        redeem_script: &Script,
+        prev_txs: &HashMap<String, Transaction>,
+    ) -> Result<()> {
+        let (_, pub_keys) = redeem_script
+            .multisig_keys()
+            .ok_or_else(|| ChainError::InvalidTx(String::from("not a multisig script")))?;
+        if !pub_keys.iter().any(|k| k[..] == private_key[32..]) {
+            return Err(ChainError::InvalidTx(String::from(
+                "key is not a cosigner of this multisig",
+            )));
+        }
+        let script_pub_key = Script::new_p2sh(&redeem_script.script_hash()?);
+        let redeem_data = bincode::serialize(redeem_script)?;
+
+        for in_id in 0..self.vin.len() {
+            match prev_output(prev_txs, &self.vin[in_id])? {
+                Some(out) if out.script_pub_key == script_pub_key => {}
+                _ => continue,
+            }
+            let sighash = self.signature_hash(in_id, &script_pub_key)?;
+            // keep the cosigners' signatures that check out, dropping anything
+            // else an untrusted script_sig pushed
+            let mut signatures: Vec<Vec<u8>> = match self.vin[in_id].script_sig.0.split_last() {
+                Some((Op::Push(data), sigs)) if *data == redeem_data => sigs
+                    .iter()
+                    .filter_map(|op| match op {
+                        Op::Push(sig)
+                            if pub_keys
+                                .iter()
+                                .any(|key| script::verify_signature(&sighash, key, sig)) =>
+                        {
+                            Some(sig.clone())
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            signatures.push(ed25519::signature(&sighash, private_key).to_vec());
+            self.vin[in_id].script_sig =
+                Script::new_multisig_sig(&sighash, &signatures, redeem_script)?;
         }
 
         Ok(())
     }
 
-    fn hash(&mut self) -> Result<String> {
-        self.id = String::new();
-        let data = bincode::serialize(self)?;
+    /// Signs with every key in `wallets`, including their multisig shares.
+    pub fn sign_with_wallets(
+        &mut self,
+        wallets: &Wallets,
+        prev_txs: &HashMap<String, Transaction>,
+    ) -> Result<()> {
+        wallets.check_unlocked()?;
+        for wallet in wallets.get_wallets() {
+            self.sign(&wallet.secret_key, prev_txs)?;
+        }
+        for redeem_script in wallets.get_multisigs() {
+            let pub_keys = match redeem_script.multisig_keys() {
+                Some((_, pub_keys)) => pub_keys,
+                None => continue,
+            };
+            for wallet in wallets.get_wallets() {
+                if pub_keys.contains(&wallet.public_key) {
+                    self.sign_multisig(&wallet.secret_key, redeem_script, prev_txs)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialized size in bytes, the denominator of fee rates.
+    pub fn size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(bincode::serialize(self)?))
+    }
+
+    pub fn from_hex(data: &str) -> Result<Transaction> {
+        let bytes = hex::decode(data).map_err(|e| ChainError::Encoding(e.to_string()))?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    /// Digest signed for input `in_id`: the transaction with every unlocking
+    /// script cleared and the spent output's locking script in its place.
+    pub fn signature_hash(&self, in_id: usize, prev_script: &Script) -> Result<Vec<u8>> {
+        let mut tx_copy = self.clone();
+        tx_copy.id = String::new();
+        for input in &mut tx_copy.vin {
+            input.script_sig = Script::default();
+        }
+        tx_copy.vin[in_id].script_sig = prev_script.clone();
+        let data = bincode::serialize(&tx_copy)?;
         let mut hasher = Sha256::new();
         hasher.input(&data[..]);
-        Ok(hasher.result_str())
+        let mut digest = vec![0; hasher.output_bytes()];
+        hasher.result(&mut digest);
+        Ok(digest)
     }
 
-    fn trim_copy(&self) -> Transaction {
-        let mut vin = Vec::new();
-        let mut vout = Vec::new();
-        for input in &self.vin {
-            vin.push(TxInput {
-                txid: input.txid.clone(),
-                vout: input.vout,
-                signature: Vec::new(),
-                pub_key: Vec::new(),
-            });
+    /// Hashes the transaction without its id and unlocking scripts, so signing
+    /// does not change it. Coinbase data stays in to keep coinbase ids distinct.
+    fn hash(&self) -> Result<String> {
+        let mut copy = self.clone();
+        copy.id = String::new();
+        if !copy.is_coinbase() {
+            for input in &mut copy.vin {
+                input.script_sig = Script::default();
+            }
         }
-        for output in &self.vout {
-            vout.push(TxOutput {
-                value: output.value,
-                pub_key_hash: output.pub_key_hash.clone(),
-            });
+        let data = bincode::serialize(&copy)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        Ok(hasher.result_str())
+    }
+}
+
+/// Looks up the output spent by `input`, erroring if its transaction is missing.
+fn prev_output<'a>(
+    prev_txs: &'a HashMap<String, Transaction>,
+    input: &TxInput,
+) -> Result<Option<&'a TxOutput>> {
+    match prev_txs.get(&input.txid) {
+        Some(prev_tx) if !prev_tx.id.is_empty() => Ok(prev_tx.vout.get(input.vout as usize)),
+        _ => Err(ChainError::InvalidTx(format!(
+            "previous transaction {} is not correct",
+            input.txid
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A transaction spending output 0 of `prev`, which pays `script_pub_key`.
+    fn spend(script_pub_key: Script) -> (Transaction, HashMap<String, Transaction>) {
+        let prev = Transaction {
+            id: String::from("prev"),
+            vin: Vec::new(),
+            vout: vec![TxOutput {
+                value: Amount::from_base(1_000),
+                script_pub_key,
+            }],
+            lock_time: 0,
+        };
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new(prev.id.clone(), 0, SEQUENCE_FINAL)],
+            vout: Vec::new(),
+            lock_time: 0,
+        };
+        tx.set_id().unwrap();
+        (tx, HashMap::from([(prev.id.clone(), prev)]))
+    }
+
+    fn utxo(txid: &str, value: u64) -> Utxo {
+        Utxo {
+            txid: txid.to_string(),
+            vout: 0,
+            output: TxOutput {
+                value: Amount::from_base(value),
+                script_pub_key: Script::new_p2pkh(&[0; 20]),
+            },
         }
+    }
 
-        Transaction {
-            id: self.id.clone(),
-            vin,
-            vout,
+    fn fund_with(utxos: &[(&str, i32)], candidates: Vec<Utxo>) -> Result<Transaction> {
+        let options = TxOptions {
+            utxos: utxos
+                .iter()
+                .map(|(txid, vout)| (txid.to_string(), *vout))
+                .collect(),
+            ..TxOptions::default()
+        };
+        let vout = vec![TxOutput {
+            value: Amount::from_base(1_000),
+            script_pub_key: Script::new_p2pkh(&[1; 20]),
+        }];
+        Transaction::fund("test", Vec::new(), candidates, None, vout, &options)
+    }
+
+    #[test]
+    fn fund_spends_the_chosen_outputs() {
+        let candidates = vec![utxo("a", 5_000), utxo("b", 2_000)];
+        let tx = fund_with(&[("b", 0)], candidates).unwrap();
+        assert_eq!(tx.vin.len(), 1);
+        assert_eq!(tx.vin[0].txid, "b");
+        // change goes back to the input's script
+        assert_eq!(tx.vout[1].value, Amount::from_base(1_000));
+        assert_eq!(tx.vout[1].script_pub_key, Script::new_p2pkh(&[0; 20]));
+    }
+
+    #[test]
+    fn fund_rejects_unknown_and_repeated_outputs() {
+        let candidates = vec![utxo("a", 5_000)];
+        assert!(matches!(
+            fund_with(&[("c", 0)], candidates.clone()),
+            Err(ChainError::InvalidTx(_))
+        ));
+        assert!(matches!(
+            fund_with(&[("a", 1)], candidates.clone()),
+            Err(ChainError::InvalidTx(_))
+        ));
+        assert!(matches!(
+            fund_with(&[("a", 0), ("a", 0)], candidates),
+            Err(ChainError::InvalidTx(_))
+        ));
+    }
+
+    #[test]
+    fn fund_reports_insufficient_funds() {
+        let candidates = vec![utxo("a", 600), utxo("b", 300)];
+        match fund_with(&[], candidates.clone()) {
+            Err(ChainError::InsufficientFunds { have, need }) => {
+                assert_eq!(have, Amount::from_base(900));
+                assert_eq!(need, Amount::from_base(1_000));
+            }
+            other => panic!("expected insufficient funds, got {:?}", other),
         }
+        assert!(matches!(
+            fund_with(&[("a", 0)], candidates),
+            Err(ChainError::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_spending_an_output_twice() {
+        let (mut tx, prev_txs) = spend(Script::new_p2pkh(&[0; 20]));
+        tx.vin.push(tx.vin[0].clone());
+        assert!(matches!(
+            tx.verify(&prev_txs, 1, 0),
+            Err(ChainError::InvalidTx(_))
+        ));
+    }
+
+    #[test]
+    fn consensus_accepts_any_well_formed_data_output() {
+        let (mut tx, prev_txs) = spend(Script::new_p2pkh(&[0; 20]));
+        tx.vout = vec![
+            TxOutput::new_data(&[0; 1_000]),
+            TxOutput::new_data(b"second"),
+        ];
+        tx.check_data_outputs().unwrap();
+
+        tx.vout[1].value = Amount::from_base(1);
+        assert!(tx.check_data_outputs().is_err());
+        tx.vout[1] = TxOutput {
+            value: Amount::ZERO,
+            script_pub_key: Script(vec![Op::Return]),
+        };
+        assert!(tx.verify(&prev_txs, 1, 0).is_err());
+    }
+
+    #[test]
+    fn sign_multisig_drops_malformed_signature_pushes() {
+        let (secret_key, public_key) = ed25519::keypair(&[1; 32]);
+        let (_, other_key) = ed25519::keypair(&[2; 32]);
+        let redeem_script = Script::new_multisig(1, &[public_key.to_vec(), other_key.to_vec()]);
+        let script_pub_key = Script::new_p2sh(&redeem_script.script_hash().unwrap());
+        let (mut tx, prev_txs) = spend(script_pub_key);
+        tx.vin[0].script_sig = Script(vec![
+            Op::Push(vec![1]),
+            Op::Push(bincode::serialize(&redeem_script).unwrap()),
+        ]);
+
+        tx.sign_multisig(&secret_key, &redeem_script, &prev_txs)
+            .unwrap();
+        assert_eq!(tx.vin[0].script_sig.0.len(), 2);
+        assert!(!tx.vin[0].script_sig.0.contains(&Op::Push(vec![1])));
+        assert!(tx.verify(&prev_txs, 1, 0).unwrap());
     }
 }
@@ -1,29 +1,157 @@
-use crate::error::Result;
-use crate::transaction::Transaction;
-use std::collections::HashMap;
+use crate::amount::Amount;
+use crate::error::{ChainError, Result};
+use crate::script::Script;
+use crate::wallet::{address_to_script, hash_pub_key};
+
+/// Sequence of an input that opts out of both lock time and relative locks.
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// Highest sequence that still opts the transaction in to replace-by-fee.
+pub const MAX_BIP125_RBF_SEQUENCE: u32 = 0xffff_fffd;
+/// Set on a sequence to disable its relative lock.
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// Set on a sequence to count its relative lock in 512-second units, not blocks.
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0xffff;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u64 = 512;
+
+/// How long after the spent output confirmed an input may be mined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeLock {
+    Blocks(usize),
+    Seconds(u64),
+}
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TxInput {
     pub txid: String,
     pub vout: i32,
-    pub signature: Vec<u8>,
-    pub pub_key: Vec<u8>,
+    pub script_sig: Script, //unlocking script
+    pub sequence: u32,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TxOutput {
-    pub value: i32,
-    pub pub_key_hash: Vec<u8>, //locking script
+    pub value: Amount,
+    pub script_pub_key: Script, //locking script
+}
+
+impl RelativeLock {
+    /// The input sequence enforcing the lock, rounding seconds up to the
+    /// 512-second granularity. Errors if the lock is too long to encode.
+    pub fn to_sequence(self) -> Result<u32> {
+        let (value, flag) = match self {
+            RelativeLock::Blocks(blocks) => (blocks as u64, 0),
+            RelativeLock::Seconds(seconds) => (
+                seconds.div_ceil(SEQUENCE_LOCKTIME_GRANULARITY),
+                SEQUENCE_LOCKTIME_TYPE_FLAG,
+            ),
+        };
+        if value > SEQUENCE_LOCKTIME_MASK as u64 {
+            return Err(ChainError::InvalidTx(format!(
+                "relative locks are at most {} blocks or {} seconds",
+                SEQUENCE_LOCKTIME_MASK,
+                SEQUENCE_LOCKTIME_MASK as u64 * SEQUENCE_LOCKTIME_GRANULARITY
+            )));
+        }
+        Ok(value as u32 | flag)
+    }
 }
 
 impl TxInput {
-    pub fn can_unlock_output_with(&self, unlocking_data: &str) -> bool {
-        self.script_sig == unlocking_data
+    pub fn new(txid: String, vout: i32, sequence: u32) -> TxInput {
+        TxInput {
+            txid,
+            vout,
+            script_sig: Script::default(),
+            sequence,
+        }
+    }
+
+    pub fn relative_lock(&self) -> Option<RelativeLock> {
+        if self.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = self.sequence & SEQUENCE_LOCKTIME_MASK;
+        if self.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLock::Seconds(
+                value as u64 * SEQUENCE_LOCKTIME_GRANULARITY,
+            ))
+        } else {
+            Some(RelativeLock::Blocks(value as usize))
+        }
+    }
+
+    /// Checks whether the input spends with the key hashing to `pub_key_hash`.
+    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
+        match self.script_sig.p2pkh_pub_key() {
+            Some(pub_key) => {
+                let mut locking_hash = pub_key.to_vec();
+                hash_pub_key(&mut locking_hash);
+                locking_hash == pub_key_hash
+            }
+            None => false,
+        }
     }
 }
 
 impl TxOutput {
-    pub fn can_be_unlocked_with(&self, unlocking_data: &str) -> bool {
-        self.script_pub_key == unlocking_data
+    pub fn new(value: Amount, address: &str) -> Result<TxOutput> {
+        let mut txo = TxOutput {
+            value,
+            script_pub_key: Script::default(),
+        };
+        txo.lock(address)?;
+        Ok(txo)
+    }
+
+    /// A provably unspendable output carrying `data` and no value.
+    pub fn new_data(data: &[u8]) -> TxOutput {
+        TxOutput {
+            value: Amount::ZERO,
+            script_pub_key: Script::new_data(data),
+        }
+    }
+
+    /// Locks the output to the pub-key-hash or script hash encoded in `address`.
+    pub fn lock(&mut self, address: &str) -> Result<()> {
+        self.script_pub_key = address_to_script(address)?;
+        Ok(())
+    }
+
+    pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
+        self.script_pub_key.p2pkh_hash() == Some(pub_key_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(sequence: u32) -> TxInput {
+        TxInput::new(String::from("prev"), 0, sequence)
+    }
+
+    #[test]
+    fn relative_locks_round_trip_through_sequences() {
+        for lock in [
+            RelativeLock::Blocks(0),
+            RelativeLock::Blocks(144),
+            RelativeLock::Seconds(1024),
+        ] {
+            assert_eq!(
+                input(lock.to_sequence().unwrap()).relative_lock(),
+                Some(lock)
+            );
+        }
+        // seconds round up to whole 512-second units
+        let sequence = RelativeLock::Seconds(600).to_sequence().unwrap();
+        assert_eq!(
+            input(sequence).relative_lock(),
+            Some(RelativeLock::Seconds(1024))
+        );
+        assert!(RelativeLock::Blocks(65_536).to_sequence().is_err());
+        assert!(RelativeLock::Seconds(65_536 * 512).to_sequence().is_err());
+        assert_eq!(input(SEQUENCE_FINAL).relative_lock(), None);
+        assert_eq!(input(MAX_BIP125_RBF_SEQUENCE).relative_lock(), None);
     }
 }
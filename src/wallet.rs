@@ -1,4 +1,12 @@
-use crate::error::Result;
+use crate::amount::Amount;
+use crate::base58;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypter::{self, KdfParams, Sealed, KEY_SIZE};
+use crate::error::{ChainError, Result};
+use crate::hd::{ExtendedKey, ACCOUNT_PATH, CHANGE_CHAIN, GAP_LIMIT, HARDENED, RECEIVE_CHAIN};
+use crate::mnemonic::{Mnemonic, DEFAULT_WORDS};
+use crate::script::Script;
 use bitcoincash_addr::{Address, HashType, Scheme};
 use crypto::digest::Digest;
 use crypto::ed25519;
@@ -9,6 +17,15 @@ use rand::RngCore;
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix byte of an encoded private key, as for Bitcoin's WIF.
+const PRIVATE_KEY_VERSION: u8 = 0x80;
+/// Prepended to every signed message, so a message signature can never pass
+/// for a transaction signature or anything else signed with the same key.
+const MESSAGE_MAGIC: &[u8] = b"my-chain Signed Message:\n";
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Wallet {
@@ -27,22 +44,137 @@ impl Wallet {
         }
     }
 
-    pub fn get_address(&self) -> String {
+    /// Rebuilds the key pair from the 32-byte seed an ed25519 secret key
+    /// starts with.
+    pub fn from_seed(seed: &[u8]) -> Wallet {
+        let (secret_key, public_key) = ed25519::keypair(seed);
+        Wallet {
+            secret_key: secret_key.to_vec(),
+            public_key: public_key.to_vec(),
+        }
+    }
+
+    pub fn get_address(&self) -> Result<String> {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
-        address.encode().unwrap()
+        pub_key_hash_to_address(&pub_hash)
+    }
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn pub_key_hash_to_address(pub_key_hash: &[u8]) -> Result<String> {
+    encode_address(pub_key_hash, HashType::Key)
+}
+
+/// Encodes the hash of a redeem script, e.g. a multisig script, as an address.
+pub fn script_hash_to_address(script_hash: &[u8]) -> Result<String> {
+    encode_address(script_hash, HashType::Script)
+}
+
+fn encode_address(hash: &[u8], hash_type: HashType) -> Result<String> {
+    let address = Address {
+        body: hash.to_vec(),
+        scheme: Scheme::Base58,
+        hash_type,
+        ..Default::default()
+    };
+    address
+        .encode()
+        .map_err(|e| ChainError::InvalidAddress(format!("{:?}", e)))
+}
+
+fn decode_address(address: &str) -> Result<Address> {
+    let decoded =
+        Address::decode(address).map_err(|_| ChainError::InvalidAddress(address.to_string()))?;
+    if decoded.scheme != Scheme::Base58 || decoded.body.len() != 20 {
+        return Err(ChainError::InvalidAddress(address.to_string()));
+    }
+    Ok(decoded)
+}
+
+/// Decodes a base58check address into its pub-key-hash, rejecting bad checksums.
+pub fn address_to_pub_key_hash(address: &str) -> Result<Vec<u8>> {
+    let decoded = decode_address(address)?;
+    if decoded.hash_type != HashType::Key {
+        return Err(ChainError::InvalidAddress(address.to_string()));
+    }
+    Ok(decoded.body)
+}
+
+/// Builds the locking script paying to `address`, whether key or script hash.
+pub fn address_to_script(address: &str) -> Result<Script> {
+    let decoded = decode_address(address)?;
+    Ok(match decoded.hash_type {
+        HashType::Key => Script::new_p2pkh(&decoded.body),
+        HashType::Script => Script::new_p2sh(&decoded.body),
+    })
+}
+
+/// Encodes the seed of `secret_key` as base58check text for `dumpprivkey`.
+pub fn encode_private_key(secret_key: &[u8]) -> String {
+    let mut payload = vec![PRIVATE_KEY_VERSION];
+    payload.extend_from_slice(&secret_key[..32]);
+    base58::encode_check(&payload)
+}
+
+/// Decodes text from `encode_private_key` back into the 32-byte seed.
+pub fn decode_private_key(key: &str) -> Result<Vec<u8>> {
+    let payload = base58::decode_check(key)?;
+    match payload.split_first() {
+        Some((&PRIVATE_KEY_VERSION, seed)) if seed.len() == 32 => Ok(seed.to_vec()),
+        _ => Err(ChainError::Encoding(String::from("not a private key"))),
     }
 }
 
+/// The address an output pays, if it is a plain key or script hash.
+pub fn script_to_address(script: &Script) -> Result<Option<String>> {
+    if let Some(hash) = script.p2pkh_hash() {
+        return Ok(Some(pub_key_hash_to_address(hash)?));
+    }
+    if let Some(hash) = script.p2sh_hash() {
+        return Ok(Some(script_hash_to_address(hash)?));
+    }
+    Ok(None)
+}
+
+/// SHA256 of the magic prefix, the message length and the message.
+fn message_hash(message: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(MESSAGE_MAGIC);
+    hasher.input(&(message.len() as u64).to_le_bytes());
+    hasher.input(message.as_bytes());
+    let mut hash = vec![0; 32];
+    hasher.result(&mut hash);
+    hash
+}
+
+/// Checks a signature from `Wallets::sign_message`: its public key must
+/// hash to `address` and its signature must cover `message`.
+pub fn verify_message(address: &str, signature: &str, message: &str) -> Result<bool> {
+    let pub_key_hash = address_to_pub_key_hash(address)?;
+    let signature = hex::decode(signature)
+        .map_err(|_| ChainError::Encoding(String::from("signature is not hex")))?;
+    if signature.len() != 96 {
+        return Err(ChainError::Encoding(String::from(
+            "signature must be 96 bytes: public key then signature",
+        )));
+    }
+    let (pub_key, signature) = signature.split_at(32);
+    let mut hash = pub_key.to_vec();
+    hash_pub_key(&mut hash);
+    Ok(hash == pub_key_hash && ed25519::verify(&message_hash(message), pub_key, signature))
+}
+
+/// Hash160 of `pub_key` in place: RIPEMD160(SHA256(data)).
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
     hasher1.input(pub_key);
+    pub_key.resize(32, 0);
     hasher1.result(pub_key);
     let mut hasher2 = Ripemd160::new();
     hasher2.input(pub_key);
@@ -50,37 +182,769 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     hasher2.result(pub_key);
 }
 
+/// A secret key sealed under the wallet's master key, bound to its address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CryptedKey {
+    public_key: Vec<u8>,
+    secret_key: Sealed,
+}
+
+/// The random key sealing every secret, itself sealed under the passphrase.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MasterKey {
+    kdf: KdfParams,
+    key: Sealed,
+}
+
+/// The master key sealed under a random session key, kept until `expires`
+/// in unix seconds. The session key lives outside the wallet database, in
+/// the file `session_key_path(id)` names, so the database alone never holds
+/// enough to sign.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UnlockSession {
+    id: String,
+    key: Sealed,
+    expires: u64,
+}
+
+/// The seed every derived key comes from, with the mnemonic it was stretched
+/// from so it can be shown again for backup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HdSeed {
+    mnemonic: String,
+    seed: Vec<u8>,
+}
+
+/// Where a derived key sits under `hd::ACCOUNT_PATH`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct KeyPath {
+    chain: u32,
+    index: u32,
+}
+
+/// The public half of a derived key, cached so an encrypted wallet can list
+/// its addresses without the seed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DerivedKey {
+    path: KeyPath,
+    public_key: Vec<u8>,
+}
+
+/// A confirmed output paying one of the wallet's addresses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedOutput {
+    pub txid: String,
+    pub vout: i32,
+    pub address: String,
+    pub value: Amount,
+    pub height: usize,
+}
+
+/// A confirmed transaction paying to or spending from the wallet, with what
+/// it did so a disconnected block can be undone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletTx {
+    pub txid: String,
+    pub height: usize,
+    /// Position in its block, to list transactions in chain order.
+    pub index: usize,
+    pub received: Vec<OwnedOutput>,
+    pub spent: Vec<OwnedOutput>,
+}
+
+/// Every key the node holds. Keys come from one HD seed, so a backup of the
+/// seed and the next index of each chain recovers them all; keys created
+/// before the seed existed are kept as standalone keys. Once encrypted,
+/// secrets are only stored sealed and wallets load with empty secret keys
+/// unless an unlock session is live. Watch-only addresses are tracked for
+/// balances and history but hold no key to sign with. The outputs and
+/// transactions of all these addresses are kept up to date as blocks are
+/// connected, up to the `synced` height. Labels name the wallet's own
+/// addresses and contacts name other people's.
 pub struct Wallets {
+    /// Directory of the sled database holding this wallet.
+    path: String,
     wallets: HashMap<String, Wallet>,
+    multisigs: HashMap<String, Script>,
+    master: Option<MasterKey>,
+    crypted: HashMap<String, CryptedKey>,
+    master_key: Option<Vec<u8>>,
+    /// `None` until the first key is derived, and while locked.
+    seed: Option<HdSeed>,
+    sealed_seed: Option<Sealed>,
+    /// Next unissued index on the receiving and change chains.
+    next_index: [u32; 2],
+    derived: HashMap<String, KeyPath>,
+    /// Watched addresses, with the public key when one was imported.
+    watch_only: HashMap<String, Option<Vec<u8>>>,
+    utxos: HashMap<(String, i32), OwnedOutput>,
+    transactions: HashMap<String, WalletTx>,
+    synced: Option<usize>,
+    labels: HashMap<String, String>,
+    /// Address book of external addresses by name.
+    contacts: HashMap<String, String>,
 }
 
 impl Wallets {
+    /// Opens the default wallet at `data/wallets`.
     pub fn new() -> Result<Wallets> {
+        Wallets::open("data/wallets")
+    }
+
+    /// Opens the wallet stored at `path`, creating it if there is none.
+    pub fn open(path: &str) -> Result<Wallets> {
         let mut wlt = Wallets {
+            path: path.to_string(),
             wallets: HashMap::<String, Wallet>::new(),
+            multisigs: HashMap::<String, Script>::new(),
+            master: None,
+            crypted: HashMap::new(),
+            master_key: None,
+            seed: None,
+            sealed_seed: None,
+            next_index: [0; 2],
+            derived: HashMap::new(),
+            watch_only: HashMap::new(),
+            utxos: HashMap::new(),
+            transactions: HashMap::new(),
+            synced: None,
+            labels: HashMap::new(),
+            contacts: HashMap::new(),
         };
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(path)?;
         for item in db.into_iter() {
+            let i = item?;
+            let wallet: Wallet = bincode::deserialize(&i.1)?;
+            // re-derive the key so wallets stored under an older encoding load too
+            wlt.wallets.insert(wallet.get_address()?, wallet);
+        }
+        for item in db.open_tree("multisig")?.into_iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
-            wlt.wallets.insert(address, wallet);
+            let redeem_script = bincode::deserialize(&i.1)?;
+            wlt.multisigs.insert(address, redeem_script);
+        }
+        for item in db.open_tree("watch")?.into_iter() {
+            let i = item?;
+            let address = String::from_utf8(i.0.to_vec())?;
+            wlt.watch_only.insert(address, bincode::deserialize(&i.1)?);
+        }
+        for item in db.open_tree("label")?.into_iter() {
+            let i = item?;
+            wlt.labels.insert(
+                String::from_utf8(i.0.to_vec())?,
+                String::from_utf8(i.1.to_vec())?,
+            );
+        }
+        for item in db.open_tree("contact")?.into_iter() {
+            let i = item?;
+            wlt.contacts.insert(
+                String::from_utf8(i.0.to_vec())?,
+                String::from_utf8(i.1.to_vec())?,
+            );
+        }
+        for item in db.open_tree("utxo")?.into_iter() {
+            let out: OwnedOutput = bincode::deserialize(&item?.1)?;
+            wlt.utxos.insert((out.txid.clone(), out.vout), out);
+        }
+        for item in db.open_tree("tx")?.into_iter() {
+            let i = item?;
+            wlt.transactions
+                .insert(String::from_utf8(i.0.to_vec())?, bincode::deserialize(&i.1)?);
+        }
+        if let Some(height) = db.open_tree("sync")?.get("height")? {
+            wlt.synced = Some(bincode::deserialize(&height)?);
+        }
+        let hd = db.open_tree("hd")?;
+        if let Some(next_index) = hd.get("index")? {
+            wlt.next_index = bincode::deserialize(&next_index)?;
+        }
+        if let Some(seed) = hd.get("seed")? {
+            wlt.seed = Some(bincode::deserialize(&seed)?);
+        }
+        let crypt = db.open_tree("crypt")?;
+        if let Some(master) = crypt.get("master")? {
+            wlt.master = Some(bincode::deserialize(&master)?);
+            if let Some(seed) = crypt.get("seed")? {
+                wlt.sealed_seed = Some(bincode::deserialize(&seed)?);
+            }
+            for item in db.open_tree("crypted")?.into_iter() {
+                let i = item?;
+                let address = String::from_utf8(i.0.to_vec())?;
+                let crypted: CryptedKey = bincode::deserialize(&i.1)?;
+                let wallet = Wallet {
+                    secret_key: Vec::new(),
+                    public_key: crypted.public_key.clone(),
+                };
+                wlt.wallets.insert(address.clone(), wallet);
+                wlt.crypted.insert(address, crypted);
+            }
+            for item in db.open_tree("hdkeys")?.into_iter() {
+                let i = item?;
+                let address = String::from_utf8(i.0.to_vec())?;
+                let derived: DerivedKey = bincode::deserialize(&i.1)?;
+                let wallet = Wallet {
+                    secret_key: Vec::new(),
+                    public_key: derived.public_key,
+                };
+                wlt.wallets.insert(address.clone(), wallet);
+                wlt.derived.insert(address, derived.path);
+            }
+            let unlock = db.open_tree("unlock")?;
+            if let Some(session) = unlock.get("session")? {
+                let session: UnlockSession = bincode::deserialize(&session)?;
+                match open_session(&session)? {
+                    Some(master_key) => wlt.unseal_keys(master_key)?,
+                    None => {
+                        unlock.remove("session")?;
+                        unlock.flush()?;
+                    }
+                }
+            }
+        } else {
+            wlt.derive_keys()?;
         }
         drop(db);
         Ok(wlt)
     }
 
-    pub fn create_wallet(&mut self) -> Result<String> {
-        let wallet = Wallet::new();
-        let address = wallet.get_address();
+    pub fn is_encrypted(&self) -> bool {
+        self.master.is_some()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.is_encrypted() && self.master_key.is_none()
+    }
+
+    /// Errors unless secret keys are available for signing.
+    pub fn check_unlocked(&self) -> Result<()> {
+        if self.is_locked() {
+            return Err(ChainError::WalletLocked);
+        }
+        Ok(())
+    }
+
+    /// Seals every secret key under a new master key derived from
+    /// `passphrase`, leaving the wallet locked. Call `save_all` to persist;
+    /// sled may keep the old plaintext records in its log until it compacts.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.is_encrypted() {
+            return Err(ChainError::Wallet(String::from("wallet is already encrypted")));
+        }
+        let master_key = crypter::random_bytes(KEY_SIZE);
+        let kdf = KdfParams::new();
+        let key = crypter::seal(&kdf.derive_key(passphrase.as_bytes()), &master_key, b"master");
+        for (address, wallet) in self.wallets.iter_mut() {
+            // derived keys are recovered from the sealed seed instead
+            if !self.derived.contains_key(address) {
+                let crypted = CryptedKey {
+                    public_key: wallet.public_key.clone(),
+                    secret_key: crypter::seal(&master_key, &wallet.secret_key, address.as_bytes()),
+                };
+                self.crypted.insert(address.clone(), crypted);
+            }
+            wallet.secret_key.clear();
+        }
+        if let Some(seed) = self.seed.take() {
+            self.sealed_seed = Some(crypter::seal(&master_key, &bincode::serialize(&seed)?, b"seed"));
+        }
+        self.master = Some(MasterKey { kdf, key });
+        Ok(())
+    }
+
+    /// Unseals the secret keys with `passphrase` and keeps them available to
+    /// later commands for `timeout` seconds. With no daemon to hold the key in
+    /// memory, the master key is stored in the wallet's database sealed under
+    /// a random session key, which is written to a file readable only by the
+    /// current user in the runtime directory (`$XDG_RUNTIME_DIR`, or
+    /// `/dev/shm`, both normally in memory). Anyone able to read that file and
+    /// the wallet while the session lasts can sign, so keep `timeout` short.
+    /// The file is deleted when the session expires or `lock` is called,
+    /// leaving any copy of the sealed key sled keeps in its log useless.
+    pub fn unlock(&mut self, passphrase: &str, timeout: u64) -> Result<()> {
+        let master = self
+            .master
+            .as_ref()
+            .ok_or_else(|| ChainError::Wallet(String::from("wallet is not encrypted")))?;
+        let master_key = crypter::open(
+            &master.kdf.derive_key(passphrase.as_bytes()),
+            &master.key,
+            b"master",
+        )
+        .ok_or_else(|| ChainError::Wallet(String::from("incorrect passphrase")))?;
+        self.unseal_keys(master_key.clone())?;
+
+        let session_key = crypter::random_bytes(KEY_SIZE);
+        let session = UnlockSession {
+            id: hex::encode(crypter::random_bytes(16)),
+            key: crypter::seal(&session_key, &master_key, b"session"),
+            expires: now()?.saturating_add(timeout),
+        };
+        write_session_key(&session.id, &session_key)?;
+        let db = sled::open(&self.path)?;
+        let unlock = db.open_tree("unlock")?;
+        let old = unlock.insert("session", bincode::serialize(&session)?)?;
+        db.flush()?;
+        drop(db);
+        if let Some(old) = old {
+            let old: UnlockSession = bincode::deserialize(&old)?;
+            remove_session_key(&old.id);
+        }
+        Ok(())
+    }
+
+    /// Forgets the unsealed keys and ends any unlock session.
+    pub fn lock(&mut self) -> Result<()> {
+        if !self.is_encrypted() {
+            return Err(ChainError::Wallet(String::from("wallet is not encrypted")));
+        }
+        self.master_key = None;
+        self.seed = None;
+        for wallet in self.wallets.values_mut() {
+            wallet.secret_key.clear();
+        }
+        let db = sled::open(&self.path)?;
+        let old = db.open_tree("unlock")?.remove("session")?;
+        db.flush()?;
+        drop(db);
+        if let Some(old) = old {
+            let old: UnlockSession = bincode::deserialize(&old)?;
+            remove_session_key(&old.id);
+        }
+        Ok(())
+    }
+
+    fn unseal_keys(&mut self, master_key: Vec<u8>) -> Result<()> {
+        for (address, crypted) in &self.crypted {
+            let secret_key = crypter::open(&master_key, &crypted.secret_key, address.as_bytes())
+                .ok_or_else(|| {
+                    ChainError::Wallet(format!("cannot decrypt the key of {}", address))
+                })?;
+            if let Some(wallet) = self.wallets.get_mut(address) {
+                wallet.secret_key = secret_key;
+            }
+        }
+        if let Some(sealed_seed) = &self.sealed_seed {
+            let seed = crypter::open(&master_key, sealed_seed, b"seed")
+                .ok_or_else(|| ChainError::Wallet(String::from("cannot decrypt the seed")))?;
+            self.seed = Some(bincode::deserialize(&seed)?);
+        }
+        self.master_key = Some(master_key);
+        self.derive_keys()
+    }
+
+    /// Derives every issued key plus `GAP_LIMIT` unissued ones on each chain,
+    /// so payments to addresses handed out by a restored copy are still seen.
+    fn derive_keys(&mut self) -> Result<()> {
+        let account = match &self.seed {
+            Some(seed) => ExtendedKey::master(&seed.seed).derive_path(ACCOUNT_PATH)?,
+            None => return Ok(()),
+        };
+        for chain in [RECEIVE_CHAIN, CHANGE_CHAIN] {
+            let chain_key = account.child(chain);
+            for index in 0..self.next_index[chain as usize].saturating_add(GAP_LIMIT) {
+                let wallet = chain_key.child(index).to_wallet();
+                let address = wallet.get_address()?;
+                self.derived.insert(address.clone(), KeyPath { chain, index });
+                self.wallets.insert(address, wallet);
+            }
+        }
+        Ok(())
+    }
+
+    /// Issues the next key on `chain`, creating the seed from a new mnemonic
+    /// on first use.
+    fn next_key(&mut self, chain: u32) -> Result<String> {
+        self.check_unlocked()?;
+        if self.seed.is_none() && self.sealed_seed.is_none() {
+            self.set_seed(&Mnemonic::generate(DEFAULT_WORDS)?, "")?;
+        }
+        let index = self.next_index[chain as usize];
+        self.next_index[chain as usize] = index
+            .checked_add(1)
+            .filter(|next| *next < HARDENED)
+            .ok_or_else(|| ChainError::Wallet(String::from("key chain is exhausted")))?;
+        self.derive_keys()?;
+        self.derived
+            .iter()
+            .find(|(_, path)| path.chain == chain && path.index == index)
+            .map(|(address, _)| address.clone())
+            .ok_or_else(|| ChainError::Wallet(format!("key {} was not derived", index)))
+    }
+
+    /// Notes that `address` has been seen on chain, so the keys up to it count
+    /// as issued and the lookahead moves past it. Returns whether it advanced.
+    pub fn mark_used(&mut self, address: &str) -> Result<bool> {
+        let path = match self.derived.get(address) {
+            Some(path) if path.index >= self.next_index[path.chain as usize] => *path,
+            _ => return Ok(false),
+        };
+        self.next_index[path.chain as usize] = path.index + 1;
+        if self.seed.is_some() {
+            self.derive_keys()?;
+        }
+        Ok(true)
+    }
+
+    fn set_seed(&mut self, mnemonic: &Mnemonic, passphrase: &str) -> Result<()> {
+        let seed = HdSeed {
+            mnemonic: mnemonic.to_string(),
+            seed: mnemonic.to_seed(passphrase),
+        };
+        if let Some(master_key) = &self.master_key {
+            let sealed = crypter::seal(master_key, &bincode::serialize(&seed)?, b"seed");
+            self.sealed_seed = Some(sealed);
+        }
+        self.seed = Some(seed);
+        Ok(())
+    }
+
+    /// The mnemonic backing up every derived key.
+    pub fn get_mnemonic(&self) -> Result<String> {
+        self.check_unlocked()?;
+        match &self.seed {
+            Some(seed) => Ok(seed.mnemonic.clone()),
+            None => Err(ChainError::Wallet(String::from(
+                "wallet has no seed yet, create an address first",
+            ))),
+        }
+    }
+
+    /// Derives keys from `mnemonic` and `passphrase` in place of a new seed.
+    /// Only a wallet without a seed can be restored into, so no derived key
+    /// is lost; call `rescan` afterwards to find the addresses in use.
+    pub fn restore(&mut self, mnemonic: &Mnemonic, passphrase: &str) -> Result<()> {
+        self.check_unlocked()?;
+        if self.seed.is_some() || self.sealed_seed.is_some() {
+            return Err(ChainError::Wallet(String::from(
+                "wallet already has a seed, restore into an empty data directory",
+            )));
+        }
+        self.set_seed(mnemonic, passphrase)?;
+        self.next_index = [0; 2];
+        self.derive_keys()
+    }
+
+    /// Whether the wallet owns or watches `address`, lookahead keys included.
+    pub fn is_mine(&self, address: &str) -> bool {
+        self.wallets.contains_key(address)
+            || self.multisigs.contains_key(address)
+            || self.watch_only.contains_key(address)
+    }
+
+    /// Records the outputs `block` pays to the wallet and those it spends.
+    /// A derived address paid to counts as issued, so the lookahead follows
+    /// the addresses a restored seed handed out before.
+    pub fn connect_block(&mut self, block: &Block) -> Result<()> {
+        let height = block.get_height();
+        for (index, tx) in block.get_transaction().iter().enumerate() {
+            let mut wtx = WalletTx {
+                txid: tx.id.clone(),
+                height,
+                index,
+                received: Vec::new(),
+                spent: Vec::new(),
+            };
+            for vin in &tx.vin {
+                if let Some(out) = self.utxos.remove(&(vin.txid.clone(), vin.vout)) {
+                    wtx.spent.push(out);
+                }
+            }
+            for (vout, out) in tx.vout.iter().enumerate() {
+                let address = match script_to_address(&out.script_pub_key)? {
+                    Some(address) if self.is_mine(&address) => address,
+                    _ => continue,
+                };
+                self.mark_used(&address)?;
+                let owned = OwnedOutput {
+                    txid: tx.id.clone(),
+                    vout: vout as i32,
+                    address,
+                    value: out.value,
+                    height,
+                };
+                self.utxos.insert((tx.id.clone(), vout as i32), owned.clone());
+                wtx.received.push(owned);
+            }
+            if !wtx.received.is_empty() || !wtx.spent.is_empty() {
+                self.transactions.insert(tx.id.clone(), wtx);
+            }
+        }
+        self.synced = Some(height);
+        Ok(())
+    }
+
+    /// Undoes `connect_block`, e.g. when the chain drops `block`.
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<()> {
+        for tx in block.get_transaction().iter().rev() {
+            let Some(wtx) = self.transactions.remove(&tx.id) else {
+                continue;
+            };
+            for out in wtx.received {
+                self.utxos.remove(&(out.txid, out.vout));
+            }
+            for out in wtx.spent {
+                self.utxos.insert((out.txid.clone(), out.vout), out);
+            }
+        }
+        self.synced = block.get_height().checked_sub(1);
+        Ok(())
+    }
+
+    /// Connects the blocks mined since the wallet last looked at the chain.
+    pub fn sync(&mut self, bc: &Blockchain) -> Result<()> {
+        let from = self.synced.map_or(0, |height| height + 1);
+        let mut blocks: Vec<Block> = bc.iter().take_while(|b| b.get_height() >= from).collect();
+        blocks.reverse();
+        for block in &blocks {
+            self.connect_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets what the wallet recorded from height `start` on and scans the
+    /// blocks from `start` to `end`, the tip by default, again; blocks past
+    /// `end` are picked up by the next `sync`. Use after adding keys whose
+    /// earlier payments were never recorded. Returns how many of the
+    /// wallet's transactions the scanned blocks hold. Blocks between the
+    /// synced height and `start` were never connected, so a later `start`
+    /// scans from the synced height instead.
+    pub fn rescan(&mut self, bc: &Blockchain, start: usize, end: Option<usize>) -> Result<usize> {
+        let start = start.min(self.synced.map_or(0, |height| height + 1));
+        let mut blocks: Vec<Block> = bc.iter().take_while(|b| b.get_height() >= start).collect();
+        for block in &blocks {
+            if self.synced.is_some_and(|synced| block.get_height() <= synced) {
+                self.disconnect_block(block)?;
+            }
+        }
+        self.synced = start.checked_sub(1);
+        blocks.reverse();
+        let end = end.unwrap_or(usize::MAX);
+        for block in blocks.iter().filter(|b| b.get_height() <= end) {
+            self.connect_block(block)?;
+        }
+        Ok(self
+            .transactions
+            .values()
+            .filter(|wtx| wtx.height >= start && wtx.height <= end)
+            .count())
+    }
+
+    pub fn get_synced_height(&self) -> Option<usize> {
+        self.synced
+    }
+
+    pub fn get_utxos(&self) -> impl Iterator<Item = &OwnedOutput> {
+        self.utxos.values()
+    }
+
+    /// The wallet's transactions in chain order.
+    pub fn get_transactions(&self) -> Vec<&WalletTx> {
+        let mut transactions: Vec<&WalletTx> = self.transactions.values().collect();
+        transactions.sort_by_key(|wtx| (wtx.height, wtx.index));
+        transactions
+    }
+
+    /// Adds the key encoded by `encode_private_key`. A watched address
+    /// becomes spendable.
+    pub fn import_key(&mut self, key: &str) -> Result<String> {
+        self.check_unlocked()?;
+        let wallet = Wallet::from_seed(&decode_private_key(key)?);
+        let address = wallet.get_address()?;
+        if self.wallets.contains_key(&address) {
+            return Err(ChainError::Wallet(format!(
+                "the key of {} is already in the wallet",
+                address
+            )));
+        }
+        self.watch_only.remove(&address);
+        if let Some(master_key) = &self.master_key {
+            let crypted = CryptedKey {
+                public_key: wallet.public_key.clone(),
+                secret_key: crypter::seal(master_key, &wallet.secret_key, address.as_bytes()),
+            };
+            self.crypted.insert(address.clone(), crypted);
+        }
         self.wallets.insert(address.clone(), wallet);
+        info!("Imported key of address: {}", address);
+        Ok(address)
+    }
+
+    /// Watches `address` without a key.
+    pub fn watch_address(&mut self, address: &str) -> Result<String> {
+        address_to_script(address)?;
+        self.add_watch_only(address.to_string(), None)
+    }
+
+    /// Watches the address of `public_key`, keeping the key so it can join a
+    /// multisig.
+    pub fn watch_public_key(&mut self, public_key: &[u8]) -> Result<String> {
+        if public_key.len() != 32 {
+            return Err(ChainError::InvalidAddress(String::from(
+                "public keys must be 32 bytes",
+            )));
+        }
+        let mut pub_key_hash = public_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+        let address = pub_key_hash_to_address(&pub_key_hash)?;
+        self.add_watch_only(address, Some(public_key.to_vec()))
+    }
+
+    fn add_watch_only(&mut self, address: String, public_key: Option<Vec<u8>>) -> Result<String> {
+        if self.wallets.contains_key(&address) || self.multisigs.contains_key(&address) {
+            return Err(ChainError::Wallet(format!(
+                "{} is already spendable by the wallet",
+                address
+            )));
+        }
+        self.watch_only.insert(address.clone(), public_key);
+        info!("Watching address: {}", address);
+        Ok(address)
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch_only.contains_key(address)
+    }
+
+    /// Errors if `address` is only watched, so nothing could sign for it.
+    pub fn check_can_sign(&self, address: &str) -> Result<()> {
+        if self.is_watch_only(address) {
+            return Err(ChainError::Wallet(format!(
+                "{} is watch-only, the wallet holds no key to sign for it",
+                address
+            )));
+        }
+        Ok(())
+    }
+
+    /// Labels one of the wallet's addresses; an empty label removes it.
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<()> {
+        if !self.is_mine(address) {
+            return Err(ChainError::WalletNotFound(address.to_string()));
+        }
+        if label.is_empty() {
+            self.labels.remove(address);
+        } else {
+            self.labels.insert(address.to_string(), label.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        self.labels.get(address).map(String::as_str)
+    }
+
+    /// Files `address` under `name` in the address book, replacing any
+    /// address it had. Names that are themselves addresses are refused, so
+    /// `resolve_address` is never ambiguous.
+    pub fn add_contact(&mut self, name: &str, address: &str) -> Result<()> {
+        if name.is_empty() || address_to_script(name).is_ok() {
+            return Err(ChainError::Wallet(format!(
+                "'{}' cannot name a contact",
+                name
+            )));
+        }
+        address_to_script(address)?;
+        self.contacts.insert(name.to_string(), address.to_string());
+        Ok(())
+    }
+
+    pub fn remove_contact(&mut self, name: &str) -> Result<()> {
+        self.contacts
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| ChainError::Wallet(format!("no contact named '{}'", name)))
+    }
+
+    /// Contacts as `(name, address)`, sorted by name.
+    pub fn get_contacts(&self) -> Vec<(&str, &str)> {
+        let mut contacts: Vec<(&str, &str)> = self
+            .contacts
+            .iter()
+            .map(|(name, address)| (name.as_str(), address.as_str()))
+            .collect();
+        contacts.sort();
+        contacts
+    }
+
+    /// The address of contact `to`, or `to` itself if no contact has that name.
+    pub fn resolve_address(&self, to: &str) -> String {
+        self.contacts
+            .get(to)
+            .cloned()
+            .unwrap_or_else(|| to.to_string())
+    }
+
+    /// The public key of an owned or watched address, if the wallet knows it.
+    pub fn get_public_key(&self, address: &str) -> Option<&[u8]> {
+        match self.wallets.get(address) {
+            Some(wallet) => Some(&wallet.public_key),
+            None => self.watch_only.get(address)?.as_deref(),
+        }
+    }
+
+    /// Signs `message` with the key of `address`, returning hex of the public
+    /// key followed by the signature, since an address alone does not
+    /// reveal the key to verify with.
+    pub fn sign_message(&self, address: &str, message: &str) -> Result<String> {
+        self.check_unlocked()?;
+        self.check_can_sign(address)?;
+        let wallet = self
+            .wallets
+            .get(address)
+            .ok_or_else(|| ChainError::WalletNotFound(address.to_string()))?;
+        let signature = ed25519::signature(&message_hash(message), &wallet.secret_key);
+        let mut signed = wallet.public_key.clone();
+        signed.extend_from_slice(&signature);
+        Ok(hex::encode(signed))
+    }
+
+    /// The private key of `address`, encoded for `import_key`.
+    pub fn dump_key(&self, address: &str) -> Result<String> {
+        self.check_unlocked()?;
+        self.check_can_sign(address)?;
+        let wallet = self
+            .wallets
+            .get(address)
+            .ok_or_else(|| ChainError::WalletNotFound(address.to_string()))?;
+        Ok(encode_private_key(&wallet.secret_key))
+    }
+
+    /// Issues a fresh address on the change chain, so change is not sent
+    /// back to an address that already received a payment.
+    pub fn get_change_address(&mut self) -> Result<String> {
+        self.next_key(CHANGE_CHAIN)
+    }
+
+    /// Whether `address` was derived on the change chain.
+    pub fn is_change(&self, address: &str) -> bool {
+        self.derived
+            .get(address)
+            .is_some_and(|path| path.chain == CHANGE_CHAIN)
+    }
+
+    pub fn create_wallet(&mut self) -> Result<String> {
+        let address = self.next_key(RECEIVE_CHAIN)?;
         info!("Created wallet with address: {}", address);
         Ok(address)
     }
 
+    /// Issued and watched addresses, leaving out the lookahead keys not yet
+    /// handed out.
     pub fn get_all_address(&self) -> Vec<String> {
         let mut addresses = Vec::<String>::new();
         for (k, _) in self.wallets.iter() {
+            if let Some(path) = self.derived.get(k)
+                && path.index >= self.next_index[path.chain as usize]
+            {
+                continue;
+            }
+            addresses.push(k.clone());
+        }
+        for (k, _) in self.multisigs.iter() {
+            addresses.push(k.clone());
+        }
+        for (k, _) in self.watch_only.iter() {
             addresses.push(k.clone());
         }
         addresses
@@ -90,13 +954,208 @@ impl Wallets {
         self.wallets.get(address)
     }
 
+    pub fn get_wallets(&self) -> impl Iterator<Item = &Wallet> {
+        self.wallets.values()
+    }
+
+    /// Registers an M-of-N multisig over `pub_keys` and returns its address.
+    /// Keys are sorted so every cosigner derives the same address.
+    pub fn add_multisig(&mut self, required: usize, pub_keys: &[Vec<u8>]) -> Result<String> {
+        if required == 0 || required > pub_keys.len() {
+            return Err(ChainError::InvalidAddress(format!(
+                "cannot require {} of {} signatures",
+                required,
+                pub_keys.len()
+            )));
+        }
+        if pub_keys.iter().any(|k| k.len() != 32) {
+            return Err(ChainError::InvalidAddress(String::from(
+                "public keys must be 32 bytes",
+            )));
+        }
+        let mut pub_keys = pub_keys.to_vec();
+        pub_keys.sort();
+        pub_keys.dedup();
+        let redeem_script = Script::new_multisig(required, &pub_keys);
+        let address = script_hash_to_address(&redeem_script.script_hash()?)?;
+        self.multisigs.insert(address.clone(), redeem_script);
+        info!("Created multisig with address: {}", address);
+        Ok(address)
+    }
+
+    pub fn get_multisig(&self, address: &str) -> Option<&Script> {
+        self.multisigs.get(address)
+    }
+
+    pub fn get_multisigs(&self) -> impl Iterator<Item = &Script> {
+        self.multisigs.values()
+    }
+
+    /// Persists standalone keys and the seed with its indices; derived keys
+    /// are not stored, except as a public cache once encrypted.
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
-        for (k, v) in self.wallets.iter() {
-            db.insert(k.as_bytes(), bincode::serialize(v)?)?;
+        let db = sled::open(&self.path)?;
+        let hd = db.open_tree("hd")?;
+        hd.insert("index", bincode::serialize(&self.next_index)?)?;
+        if let Some(master) = &self.master {
+            let crypted = db.open_tree("crypted")?;
+            for (k, v) in self.crypted.iter() {
+                crypted.insert(k.as_bytes(), bincode::serialize(v)?)?;
+            }
+            let hdkeys = db.open_tree("hdkeys")?;
+            for (k, path) in self.derived.iter() {
+                let derived = DerivedKey {
+                    path: *path,
+                    public_key: self.wallets[k].public_key.clone(),
+                };
+                hdkeys.insert(k.as_bytes(), bincode::serialize(&derived)?)?;
+            }
+            let crypt = db.open_tree("crypt")?;
+            crypt.insert("master", bincode::serialize(master)?)?;
+            if let Some(sealed_seed) = &self.sealed_seed {
+                crypt.insert("seed", bincode::serialize(sealed_seed)?)?;
+            }
+            // no plaintext secret may outlive encryption
+            hd.remove("seed")?;
+            db.clear()?;
+        } else {
+            for (k, v) in self.wallets.iter() {
+                if !self.derived.contains_key(k) {
+                    db.insert(k.as_bytes(), bincode::serialize(v)?)?;
+                }
+            }
+            if let Some(seed) = &self.seed {
+                hd.insert("seed", bincode::serialize(seed)?)?;
+            }
+        }
+        let multisig = db.open_tree("multisig")?;
+        for (k, v) in self.multisigs.iter() {
+            multisig.insert(k.as_bytes(), bincode::serialize(v)?)?;
+        }
+        let watch = db.open_tree("watch")?;
+        for (k, v) in self.watch_only.iter() {
+            watch.insert(k.as_bytes(), bincode::serialize(v)?)?;
+        }
+        // an imported key replaces the watch-only entry for its address
+        for k in self.wallets.keys() {
+            watch.remove(k.as_bytes())?;
+        }
+        let label = db.open_tree("label")?;
+        label.clear()?;
+        for (k, v) in self.labels.iter() {
+            label.insert(k.as_bytes(), v.as_bytes())?;
+        }
+        let contact = db.open_tree("contact")?;
+        contact.clear()?;
+        for (k, v) in self.contacts.iter() {
+            contact.insert(k.as_bytes(), v.as_bytes())?;
+        }
+        let utxo = db.open_tree("utxo")?;
+        utxo.clear()?;
+        for ((txid, vout), out) in self.utxos.iter() {
+            utxo.insert(format!("{}:{}", txid, vout), bincode::serialize(out)?)?;
+        }
+        let tx = db.open_tree("tx")?;
+        tx.clear()?;
+        for (k, v) in self.transactions.iter() {
+            tx.insert(k.as_bytes(), bincode::serialize(v)?)?;
         }
+        let sync = db.open_tree("sync")?;
+        match self.synced {
+            Some(height) => sync.insert("height", bincode::serialize(&height)?)?,
+            None => sync.remove("height")?,
+        };
         db.flush()?;
         drop(db);
         Ok(())
     }
 }
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// The master key of a live `session`, or `None` once it expired or its
+/// session key is gone, deleting the session key of an expired one.
+fn open_session(session: &UnlockSession) -> Result<Option<Vec<u8>>> {
+    if session.expires <= now()? {
+        remove_session_key(&session.id);
+        return Ok(None);
+    }
+    let Ok(session_key) = fs::read(session_key_path(&session.id)) else {
+        return Ok(None);
+    };
+    Ok(crypter::open(&session_key, &session.key, b"session"))
+}
+
+/// File holding the key of unlock session `id`, kept in memory-backed
+/// storage where the system has it so it never outlives a reboot.
+fn session_key_path(id: &str) -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_dir())
+        .or_else(|| Some(PathBuf::from("/dev/shm")).filter(|dir| dir.is_dir()))
+        .unwrap_or_else(std::env::temp_dir);
+    dir.join(format!("my-chain-unlock-{}", id))
+}
+
+fn write_session_key(id: &str, key: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let write = |options: &fs::OpenOptions| {
+        use std::io::Write;
+        options.open(session_key_path(id))?.write_all(key)
+    };
+    write(&options).map_err(|e| ChainError::Wallet(format!("cannot store the session key: {}", e)))
+}
+
+fn remove_session_key(id: &str) {
+    // already gone if the system cleared its runtime directory
+    let _ = fs::remove_file(session_key_path(id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ";
+
+    fn open(name: &str) -> Wallets {
+        let path = std::env::temp_dir().join(format!("my-chain-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        Wallets::open(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn imported_key_dumps_and_persists() {
+        let mut ws = open("import-dump");
+        let address = ws.import_key(KEY).unwrap();
+        assert_eq!(ws.dump_key(&address).unwrap(), KEY);
+        assert!(ws.import_key(KEY).is_err());
+        ws.save_all().unwrap();
+
+        let path = ws.path.clone();
+        drop(ws);
+        let ws = Wallets::open(&path).unwrap();
+        assert_eq!(ws.dump_key(&address).unwrap(), KEY);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn import_makes_a_watched_address_spendable() {
+        let mut ws = open("import-watched");
+        let seed = decode_private_key(KEY).unwrap();
+        let address = Wallet::from_seed(&seed).get_address().unwrap();
+        ws.watch_address(&address).unwrap();
+        assert!(ws.is_watch_only(&address));
+        assert!(ws.dump_key(&address).is_err());
+
+        ws.import_key(KEY).unwrap();
+        assert!(!ws.is_watch_only(&address));
+        assert_eq!(ws.dump_key(&address).unwrap(), KEY);
+        assert!(ws.watch_address(&address).is_err());
+        std::fs::remove_dir_all(&ws.path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use crate::error::{ChainError, Result};
+use crate::wallet::Wallets;
+use std::path::Path;
+
+/// The wallet commands use without `--wallet`, stored in `data/wallets`.
+pub const DEFAULT_WALLET: &str = "default";
+/// Registry of the named wallets currently loaded.
+const REGISTRY_PATH: &str = "data/walletdir";
+
+/// Directory of wallet `name`: `data/wallets` for the default wallet and
+/// `data/wallets-<name>` for the others.
+pub fn wallet_path(name: &str) -> Result<String> {
+    if name == DEFAULT_WALLET {
+        return Ok(String::from("data/wallets"));
+    }
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(ChainError::Wallet(format!(
+            "invalid wallet name '{}', use letters, digits, '-' and '_'",
+            name
+        )));
+    }
+    Ok(format!("data/wallets-{}", name))
+}
+
+pub fn exists(name: &str) -> Result<bool> {
+    Ok(Path::new(&wallet_path(name)?).exists())
+}
+
+/// Creates wallet `name` and loads it. Fails if the wallet already exists.
+pub fn create(name: &str) -> Result<Wallets> {
+    if exists(name)? {
+        return Err(ChainError::Wallet(format!(
+            "wallet {} already exists",
+            name
+        )));
+    }
+    let wallets = Wallets::open(&wallet_path(name)?)?;
+    set_loaded(name, true)?;
+    Ok(wallets)
+}
+
+/// Marks an existing wallet loaded, so commands may use it with `--wallet`
+/// and mining keeps it synced.
+///
+/// There is no long-running node process, so "loaded" is state persisted in
+/// `data/walletdir` rather than wallets held open in memory.
+pub fn load(name: &str) -> Result<()> {
+    if !exists(name)? {
+        return Err(ChainError::WalletNotFound(name.to_string()));
+    }
+    set_loaded(name, true)
+}
+
+/// Marks wallet `name` unloaded, ending any unlock session it has. The
+/// default wallet is always loaded.
+pub fn unload(name: &str) -> Result<()> {
+    if name == DEFAULT_WALLET {
+        return Err(ChainError::Wallet(String::from(
+            "the default wallet cannot be unloaded",
+        )));
+    }
+    if !is_loaded(name)? {
+        return Err(ChainError::Wallet(format!("wallet {} is not loaded", name)));
+    }
+    let mut wallets = Wallets::open(&wallet_path(name)?)?;
+    if wallets.is_encrypted() {
+        wallets.lock()?;
+    }
+    set_loaded(name, false)
+}
+
+pub fn is_loaded(name: &str) -> Result<bool> {
+    if name == DEFAULT_WALLET {
+        return Ok(true);
+    }
+    let db = sled::open(REGISTRY_PATH)?;
+    let loaded = db.contains_key(name)?;
+    drop(db);
+    Ok(loaded)
+}
+
+/// Names of the loaded wallets, the default wallet first.
+pub fn list_loaded() -> Result<Vec<String>> {
+    let mut names = vec![String::from(DEFAULT_WALLET)];
+    let db = sled::open(REGISTRY_PATH)?;
+    for item in db.iter() {
+        let (name, _) = item?;
+        names.push(String::from_utf8_lossy(&name).into_owned());
+    }
+    drop(db);
+    Ok(names)
+}
+
+/// Opens loaded wallet `name`, or the default wallet if `None`.
+pub fn open(name: Option<&str>) -> Result<Wallets> {
+    let name = name.unwrap_or(DEFAULT_WALLET);
+    if !is_loaded(name)? {
+        let reason = if exists(name)? {
+            format!("wallet {} is not loaded, load it with loadwallet", name)
+        } else {
+            format!(
+                "wallet {} does not exist, create it with createwallet --name",
+                name
+            )
+        };
+        return Err(ChainError::Wallet(reason));
+    }
+    Wallets::open(&wallet_path(name)?)
+}
+
+fn set_loaded(name: &str, loaded: bool) -> Result<()> {
+    if name == DEFAULT_WALLET {
+        return Ok(());
+    }
+    let db = sled::open(REGISTRY_PATH)?;
+    if loaded {
+        db.insert(name, &[])?;
+    } else {
+        db.remove(name)?;
+    }
+    db.flush()?;
+    drop(db);
+    Ok(())
+}